@@ -0,0 +1,97 @@
+//! Measures the time to build `FragmentTokenizer` and the memory it takes for
+//! large token sets. The size of full transition tables for the same states
+//! is computed for comparison. Run with `cargo bench`.
+
+use std::collections::HashSet;
+use std::time::Instant;
+
+use texmo::{FragmentTokenizer, Processing, TokenSet};
+
+/// A bytes token set with the most common substrings of up to `max_len`
+/// bytes starting at word boundaries of the text.
+fn token_set_from_text(text: &[u8], ntokens: usize, max_len: usize) -> TokenSet {
+    let mut token_set = TokenSet::new_bytes(Processing::Raw);
+    let mut seen = (0..=255u8).map(|b| vec![b]).collect::<HashSet<_>>();
+
+    'outer: for len in 2..=max_len {
+        for (i, window) in text.windows(len).enumerate() {
+            if i > 0 && text[i - 1] != b' ' {
+                continue;
+            }
+            if seen.insert(window.to_vec()) {
+                token_set.add_token(window);
+                if token_set.ntokens() >= ntokens {
+                    break 'outer;
+                }
+            }
+        }
+    }
+
+    token_set
+}
+
+/// The resident memory of the process in KiB, on Linux.
+fn rss_kib() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// The sizes are measured in separate processes, so that the memory freed by
+/// the previous ones doesn't hide the growth.
+const SIZE_VAR: &str = "TEXMO_BENCH_SIZE";
+
+fn main() {
+    let sizes = [(4096, 8), (32768, 16), (65536, 32)];
+    match std::env::var(SIZE_VAR) {
+        Ok(size) => measure(sizes[size.parse::<usize>().unwrap()]),
+        Err(_) => {
+            for i in 0..sizes.len() {
+                let status = std::process::Command::new(std::env::current_exe().unwrap())
+                    .env(SIZE_VAR, i.to_string())
+                    .status()
+                    .unwrap();
+                assert!(status.success());
+            }
+        }
+    }
+}
+
+fn measure((ntokens, max_len): (usize, usize)) {
+    let text = std::fs::read("data/pride.txt").unwrap();
+    let token_set = token_set_from_text(&text, ntokens, max_len);
+
+    let rss_before = rss_kib();
+    let start = Instant::now();
+    let tokenizer = FragmentTokenizer::new(token_set);
+    let build_time = start.elapsed();
+    let rss_growth = match (rss_before, rss_kib()) {
+        (Some(before), Some(after)) => format!("{:.1} MiB", (after - before) as f64 / 1024.0),
+        _ => "unknown".to_string(),
+    };
+
+    let start = Instant::now();
+    let mut buffer = Vec::new();
+    let token_ids = tokenizer.encode(&text, &mut buffer);
+    let encode_time = start.elapsed();
+
+    // Computed, not measured: the previous layout stored a `usize` target for
+    // all 256 bytes in every state.
+    let full_table_size = tokenizer.nstates() * 256 * std::mem::size_of::<usize>();
+
+    println!(
+        "{} tokens up to {} bytes: built in {:.3} s, resident memory grew by {}, \
+         {} states, automaton {:.1} MiB (computed {:.1} MiB with full tables); \
+         encoded {} bytes into {} tokens in {:.3} s",
+        tokenizer.token_set.ntokens(),
+        max_len,
+        build_time.as_secs_f64(),
+        rss_growth,
+        tokenizer.nstates(),
+        tokenizer.automaton_size() as f64 / (1 << 20) as f64,
+        full_table_size as f64 / (1 << 20) as f64,
+        text.len(),
+        token_ids.len(),
+        encode_time.as_secs_f64(),
+    );
+}
@@ -1,10 +1,14 @@
-use std::collections::HashMap;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 
+use super::digest::{self, Digest};
+use super::incremental::ChunkIndex;
 use super::input::sample::{Sample, Sampler};
 use super::stats2::TokenStats;
-use super::tokenizer2::FragmentTokenizer;
+use super::tokenizer2::{CostState, FragmentTokenizer};
 use super::tokenset::TokenSet;
 
 pub fn tokenize_file_sync<'a, S: Sampler<'a>>(
@@ -103,30 +107,408 @@ pub fn tokenize_file<'a, S: Sampler<'a>>(
     stats
 }
 
+/// Tokenizes the samples with several token sets at once, so that the samples
+/// are read only once. Every sample is tokenized with each token set on its
+/// own thread.
+pub fn tokenize_file_multi<'a, S: Sampler<'a>>(
+    token_sets: &[TokenSet],
+    sampler: &'a S,
+    initial_size: Option<u64>,
+) -> Vec<TokenStats> {
+    let tokenizers = token_sets
+        .iter()
+        .map(|token_set| FragmentTokenizer::new(token_set.clone()))
+        .collect::<Vec<_>>();
+    let mut stats = token_sets
+        .iter()
+        .map(|token_set| TokenStats::new(token_set.clone(), initial_size))
+        .collect::<Vec<_>>();
+    let mut buffers = vec![Vec::new(); token_sets.len()];
+
+    for sample in sampler.iter() {
+        let data = sample.as_bytes();
+        std::thread::scope(|scope| {
+            for ((tokenizer, stats), buffer) in tokenizers
+                .iter()
+                .zip(stats.iter_mut())
+                .zip(buffers.iter_mut())
+            {
+                scope.spawn(move || tokenizer.process_slice(data, stats, buffer));
+            }
+        });
+    }
+
+    stats
+}
+
+/// Token IDs of a sample, split into paragraphs.
+pub struct EncodedSample {
+    pub token_ids: Vec<u32>,
+    /// Number of tokens in each paragraph of the sample.
+    pub paragraph_lengths: Vec<usize>,
+}
+
+/// Returns the positions in `bytes` where new paragraphs start, i.e. the
+/// positions after an empty line followed by a non-empty one.
+pub(crate) fn paragraph_starts(bytes: &[u8]) -> Vec<usize> {
+    let mut starts = vec![0];
+    for pos in 2..bytes.len() {
+        if bytes[pos - 2] == b'\n' && bytes[pos - 1] == b'\n' && bytes[pos] != b'\n' {
+            starts.push(pos);
+        }
+    }
+    starts
+}
+
+fn encode_sample(
+    tokenizer: &FragmentTokenizer,
+    bytes: &[u8],
+    buffer: &mut Vec<CostState>,
+) -> EncodedSample {
+    let mut token_ids = Vec::new();
+    let mut paragraph_lengths = Vec::new();
+    let starts = paragraph_starts(bytes);
+
+    for (i, &start) in starts.iter().enumerate() {
+        let end = if i + 1 < starts.len() {
+            starts[i + 1]
+        } else {
+            bytes.len()
+        };
+        let paragraph = tokenizer.encode(&bytes[start..end], buffer);
+        paragraph_lengths.push(paragraph.len());
+        token_ids.extend(paragraph);
+    }
+
+    EncodedSample {
+        token_ids,
+        paragraph_lengths,
+    }
+}
+
+fn encode_worker(
+    tokenizer: &FragmentTokenizer,
+    jobs_rx: Arc<Mutex<Receiver<(usize, Sample)>>>,
+    results_tx: Sender<(usize, EncodedSample)>,
+) {
+    let mut buffer = Vec::new();
+
+    loop {
+        let job = jobs_rx.lock().unwrap().recv();
+        let (idx, sample) = match job {
+            Ok(job) => job,
+            Err(_) => break,
+        };
+
+        let encoded = encode_sample(tokenizer, sample.as_bytes(), &mut buffer);
+        results_tx.send((idx, encoded)).unwrap();
+    }
+}
+
+/// Encodes all samples from the sampler using a pool of worker threads. The
+/// encoded samples are passed to `consume` in the order of the samples.
+pub fn encode_file<'a, S: Sampler<'a>, F: FnMut(EncodedSample)>(
+    token_set: &TokenSet,
+    sampler: &'a S,
+    mut consume: F,
+) {
+    let tokenizer = FragmentTokenizer::new(token_set.clone());
+    let nthreads = std::thread::available_parallelism().unwrap().get();
+
+    let (jobs_tx, jobs_rx) = mpsc::sync_channel::<(usize, Sample)>(4);
+    let jobs_rx_shared = Arc::new(Mutex::new(jobs_rx));
+    let (results_tx, results_rx) = mpsc::channel::<(usize, EncodedSample)>();
+
+    // Samples which are encoded, but can't be consumed yet, since some of the
+    // previous samples are still being processed.
+    let mut pending = BTreeMap::new();
+    let mut next_idx = 0;
+    let mut consume_ready = |pending: &mut BTreeMap<usize, EncodedSample>| {
+        while let Some(encoded) = pending.remove(&next_idx) {
+            consume(encoded);
+            next_idx += 1;
+        }
+    };
+
+    std::thread::scope(|s| {
+        let mut join_handles = Vec::new();
+
+        for _ in 0..nthreads {
+            let jobs_rx_clone = jobs_rx_shared.clone();
+            let results_tx_clone = results_tx.clone();
+            join_handles
+                .push(s.spawn(|| encode_worker(&tokenizer, jobs_rx_clone, results_tx_clone)));
+        }
+        std::mem::drop(results_tx);
+
+        for (idx, sample) in sampler.iter().enumerate() {
+            jobs_tx.send((idx, sample)).unwrap();
+
+            while let Ok((idx, encoded)) = results_rx.try_recv() {
+                pending.insert(idx, encoded);
+            }
+            consume_ready(&mut pending);
+        }
+
+        std::mem::drop(jobs_tx);
+
+        for (idx, encoded) in results_rx.iter() {
+            pending.insert(idx, encoded);
+            consume_ready(&mut pending);
+        }
+
+        while !join_handles.is_empty() {
+            join_handles.pop().unwrap().join().unwrap();
+        }
+    });
+
+    assert!(pending.is_empty());
+}
+
+/// The number of recently computed stats kept in the incremental mode.
+const MAX_BASES: usize = 4;
+
+/// The number of stats kept in memory by `TokenizerCache`.
+const MAX_CACHED_STATS: usize = 256;
+
+/// The size of the cache directory above which `TokenizerCache` deletes the
+/// oldest files, down to three quarters of it.
+const MAX_CACHE_DIR_BYTES: u64 = 1 << 30;
+
+/// Stats in the cache, with the value of `TokenizerCache::clock` when they
+/// were last used.
+struct CacheEntry {
+    stats: TokenStats,
+    last_used: u64,
+}
+
+/// Computes and caches the stats of token sets on a sample. The stats are
+/// keyed by a digest of the sorted token set, the sample and the initial
+/// size, and can also be stored in a directory, where they survive restarts.
 pub struct TokenizerCache<'a, S: Sampler<'a>> {
     sampler: &'a S,
-    cache: HashMap<String, TokenStats>,
+    /// The digest of the sample, computed with the cache directory. `None`
+    /// without a cache directory or if the sampler returns different samples
+    /// every time, in which case the stats aren't stored on disk.
+    sample_digest: Option<u128>,
+    cache: HashMap<u128, CacheEntry>,
+    clock: u64,
+    cache_dir: Option<PathBuf>,
+    /// The size of the files in the cache directory, and its bound.
+    cache_dir_bytes: u64,
+    max_cache_dir_bytes: u64,
     initial_size: Option<u64>,
+    /// Set in the incremental mode.
+    chunk_index: Option<ChunkIndex>,
+    /// In the incremental mode, the recently computed stats with pair counts,
+    /// the most recently used first. The stats of token sets differing from
+    /// one of them by a single token are derived from it.
+    bases: VecDeque<TokenStats>,
 }
 
 impl<'a, S: Sampler<'a>> TokenizerCache<'a, S> {
     pub fn new(sampler: &'a S, initial_size: Option<u64>) -> Self {
         Self {
-            cache: HashMap::new(),
             sampler,
+            sample_digest: None,
+            cache: HashMap::new(),
+            clock: 0,
+            cache_dir: None,
+            cache_dir_bytes: 0,
+            max_cache_dir_bytes: MAX_CACHE_DIR_BYTES,
             initial_size,
+            chunk_index: None,
+            bases: VecDeque::new(),
         }
     }
 
-    pub fn get_stats_with_pairs(&mut self, token_set: &TokenSet) -> TokenStats {
+    /// Creates a cache which tokenizes the sample by paragraphs and only
+    /// retokenizes the paragraphs affected by a single added or removed token.
+    /// Keeps a copy of the sample in memory.
+    pub fn new_incremental(sampler: &'a S, initial_size: Option<u64>) -> Self {
+        Self {
+            chunk_index: Some(ChunkIndex::new(sampler)),
+            ..Self::new(sampler, initial_size)
+        }
+    }
+
+    /// Also stores the stats in `dir`, with pair counts if they were requested
+    /// by `get_stats_with_pairs`, and looks them up there when they aren't in
+    /// memory. The cache is best effort, failing to write it isn't an error.
+    pub fn with_cache_dir(self, dir: &Path) -> Self {
+        let _ = std::fs::create_dir_all(dir);
+        Self {
+            sample_digest: self.sampler.digest(),
+            cache_dir: Some(dir.to_path_buf()),
+            cache_dir_bytes: cache_files(dir).iter().map(|(_, _, len)| len).sum(),
+            ..self
+        }
+    }
+
+    /// Computes the stats of a sorted token set with pair counts. In the
+    /// incremental mode they can be derived from one of the bases or from
+    /// `related`, and the position of the base is returned too.
+    fn compute(
+        &self,
+        token_set: &TokenSet,
+        related: Option<&TokenStats>,
+    ) -> (TokenStats, Option<usize>) {
+        if let Some(stats) = self.load(self.key(token_set), token_set, true) {
+            return (stats, None);
+        }
+
+        let index = match &self.chunk_index {
+            Some(index) => index,
+            None => {
+                let stats = tokenize_file(token_set, self.sampler, self.initial_size);
+                return (stats, None);
+            }
+        };
+
+        let patched = self
+            .bases
+            .iter()
+            .chain(related)
+            .enumerate()
+            .find_map(|(pos, base)| Some((pos, index.patch_stats(base, token_set)?)));
+
+        match patched {
+            Some((pos, stats)) => (stats, Some(pos).filter(|&pos| pos < self.bases.len())),
+            None => (index.tokenize(token_set, self.initial_size), None),
+        }
+    }
+
+    /// Tokenizes the sample with a sorted token set, collecting pair counts.
+    fn tokenize(&mut self, token_set: &TokenSet) -> TokenStats {
+        let (stats, base) = self.compute(token_set, None);
+        if self.chunk_index.is_none() {
+            return stats;
+        }
+
+        if let Some(pos) = base {
+            let base = self.bases.remove(pos).unwrap();
+            let same = base.token_set.tokens == token_set.tokens
+                && base.token_set.sequences == token_set.sequences;
+            self.bases.push_front(base);
+            if same {
+                return stats;
+            }
+        }
+
+        self.bases.push_front(stats.clone());
+        self.bases.truncate(MAX_BASES);
+        stats
+    }
+
+    /// Computes the stats with pair counts of a token set without updating
+    /// the cache, so that several token sets can be evaluated in parallel. In
+    /// the incremental mode the stats can also be derived from `related`.
+    pub fn compute_stats_with_pairs(
+        &self,
+        token_set: &TokenSet,
+        related: Option<&TokenStats>,
+    ) -> TokenStats {
         let mut token_set = token_set.clone();
         token_set.sort();
+        self.compute(&token_set, related).0
+    }
 
-        let stats = tokenize_file(&token_set, self.sampler, self.initial_size);
-        let key = Self::get_key(&token_set);
+    /// Like `compute_stats_with_pairs`, but returns the cached stats if there
+    /// are any, and drops the pair counts.
+    pub fn compute_stats(&self, token_set: &TokenSet, related: Option<&TokenStats>) -> TokenStats {
+        let mut token_set = token_set.clone();
+        token_set.sort();
 
-        self.cache.insert(key, stats.clone_without_pairs());
+        let key = self.key(&token_set);
+        if let Some(entry) = self.cache.get(&key) {
+            return entry.stats.clone();
+        }
+        if let Some(stats) = self.load(key, &token_set, false) {
+            return stats;
+        }
+        self.compute(&token_set, related).0.clone_without_pairs()
+    }
+
+    /// Computes the stats of several token sets like `compute_stats_with_pairs`
+    /// (or `compute_stats` without `with_pairs`), each derived from the
+    /// corresponding `related` stats in the incremental mode. Otherwise the
+    /// token sets missing from the cache are tokenized together by
+    /// `tokenize_file_multi`.
+    pub fn compute_stats_batch(
+        &self,
+        token_sets: &[TokenSet],
+        related: &[Option<&TokenStats>],
+        with_pairs: bool,
+    ) -> Vec<TokenStats>
+    where
+        S: Sync,
+    {
+        let sorted = token_sets
+            .iter()
+            .map(|token_set| {
+                let mut token_set = token_set.clone();
+                token_set.sort();
+                token_set
+            })
+            .collect::<Vec<_>>();
+
+        let mut results = sorted
+            .iter()
+            .map(|token_set| {
+                let key = self.key(token_set);
+                match self.cache.get(&key) {
+                    Some(entry) if !with_pairs => Some(entry.stats.clone()),
+                    _ => self.load(key, token_set, with_pairs),
+                }
+            })
+            .collect::<Vec<_>>();
+        let missing = (0..sorted.len())
+            .filter(|&i| results[i].is_none())
+            .collect::<Vec<_>>();
+
+        let computed = if self.chunk_index.is_some() {
+            std::thread::scope(|scope| {
+                let handles = missing
+                    .iter()
+                    .map(|&i| {
+                        let (token_set, related) = (&sorted[i], related[i]);
+                        scope.spawn(move || self.compute(token_set, related).0)
+                    })
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .map(|h| h.join().unwrap())
+                    .collect::<Vec<_>>()
+            })
+        } else {
+            let token_sets = missing
+                .iter()
+                .map(|&i| sorted[i].clone())
+                .collect::<Vec<_>>();
+            tokenize_file_multi(&token_sets, self.sampler, self.initial_size)
+        };
 
+        for (i, stats) in missing.into_iter().zip(computed) {
+            results[i] = Some(match with_pairs {
+                true => stats,
+                false => stats.clone_without_pairs(),
+            });
+        }
+        results.into_iter().map(Option::unwrap).collect()
+    }
+
+    /// Adds stats returned by `compute_stats_with_pairs`, `compute_stats` or
+    /// `compute_stats_batch` to the cache.
+    pub fn insert(&mut self, stats: &TokenStats) {
+        self.store(stats, false);
+    }
+
+    pub fn get_stats_with_pairs(&mut self, token_set: &TokenSet) -> TokenStats {
+        let mut token_set = token_set.clone();
+        token_set.sort();
+
+        let stats = self.tokenize(&token_set);
+        self.store(&stats, true);
         stats
     }
 
@@ -134,21 +516,354 @@ impl<'a, S: Sampler<'a>> TokenizerCache<'a, S> {
         let mut token_set = token_set.clone();
         token_set.sort();
 
-        let key = Self::get_key(&token_set);
+        let key = self.key(&token_set);
+        self.clock += 1;
+        if let Some(entry) = self.cache.get_mut(&key) {
+            entry.last_used = self.clock;
+            return entry.stats.clone();
+        }
+        if let Some(stats) = self.load(key, &token_set, false) {
+            self.store(&stats, false);
+            return stats;
+        }
+
+        let stats = self.tokenize(&token_set);
+        self.store(&stats, false);
+        stats.clone_without_pairs()
+    }
 
-        if let Some(stats) = self.cache.get(&key) {
-            return stats.clone();
+    fn key(&self, token_set: &TokenSet) -> u128 {
+        let mut digest = Digest::new();
+        digest.write(&self.sample_digest.unwrap_or(0).to_le_bytes());
+        match self.initial_size {
+            Some(size) => {
+                digest.write(&[1]);
+                digest.write_u64(size);
+            }
+            None => digest.write(&[0]),
         }
+        // The pair counts around paragraph breaks depend on the mode.
+        digest.write(&[self.chunk_index.is_some() as u8]);
+        digest.write(&token_set.digest().to_le_bytes());
+        digest.finish()
+    }
 
-        let mut stats = tokenize_file(&token_set, self.sampler, self.initial_size);
-        stats.pair_counts.clear();
-        stats.pair_counts.shrink_to_fit();
-        self.cache.insert(key.clone(), stats.clone());
-        stats
+    /// Adds the stats to the disk cache, with pair counts if `with_pairs` is
+    /// set, and without them to the memory cache, evicting the least recently
+    /// used stats if it's full.
+    fn store(&mut self, stats: &TokenStats, with_pairs: bool) {
+        let key = self.key(&stats.token_set);
+        self.save(key, stats, with_pairs && !stats.pair_counts.is_empty());
+
+        self.clock += 1;
+        self.cache.insert(
+            key,
+            CacheEntry {
+                stats: stats.clone_without_pairs(),
+                last_used: self.clock,
+            },
+        );
+        if self.cache.len() > MAX_CACHED_STATS {
+            let oldest = *self
+                .cache
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .unwrap()
+                .0;
+            self.cache.remove(&oldest);
+        }
+    }
+
+    /// The paths of the files with the stats without and with pair counts.
+    fn cache_paths(&self, key: u128) -> Option<[PathBuf; 2]> {
+        self.sample_digest?;
+        let dir = self.cache_dir.as_ref()?;
+        let name = digest::to_hex(key);
+        Some([
+            dir.join(format!("{}.json", name)),
+            dir.join(format!("{}.pairs.json", name)),
+        ])
+    }
+
+    /// Reads the stats of a sorted token set from the cache directory, only
+    /// from a file with pair counts if `with_pairs` is set. Missing or
+    /// unreadable files are cache misses.
+    fn load(&self, key: u128, token_set: &TokenSet, with_pairs: bool) -> Option<TokenStats> {
+        let [path, pairs_path] = self.cache_paths(key)?;
+        let data = match with_pairs {
+            true => std::fs::read(pairs_path).ok()?,
+            false => std::fs::read(path)
+                .or_else(|_| std::fs::read(pairs_path))
+                .ok()?,
+        };
+        let value: Value = serde_json::from_slice(&data).ok()?;
+        let counts = |field: &str| -> Option<Vec<u64>> {
+            value[field]
+                .as_array()?
+                .iter()
+                .map(|c| c.as_u64())
+                .collect()
+        };
+
+        let mut stats = TokenStats::new(token_set.clone(), self.initial_size);
+        stats.total_tokens = value["total_tokens"].as_u64()?;
+        stats.scanned_bytes = value["scanned_bytes"].as_u64()?;
+        let token_counts = counts("token_counts")?;
+        let seq_counts = counts("seq_counts")?;
+        if token_counts.len() != stats.token_counts.len()
+            || seq_counts.len() != stats.seq_counts.len()
+        {
+            return None;
+        }
+        stats.token_counts = token_counts;
+        stats.seq_counts = seq_counts;
+
+        if with_pairs {
+            // Flattened (first, second, count) triples.
+            let pairs = counts("pair_counts")?;
+            if pairs.len() % 3 != 0 {
+                return None;
+            }
+            for triple in pairs.chunks(3) {
+                let pair = (triple[0] as u32, triple[1] as u32);
+                stats.pair_counts.insert(pair, triple[2]);
+            }
+        }
+        Some(stats)
+    }
+
+    /// Writes the stats to the cache directory unless they are already there.
+    /// The file is renamed into place, so that an interrupted write doesn't
+    /// leave a truncated file. Errors are ignored.
+    fn save(&mut self, key: u128, stats: &TokenStats, with_pairs: bool) {
+        let [path, pairs_path] = match self.cache_paths(key) {
+            Some(paths) => paths,
+            None => return,
+        };
+        if pairs_path.exists() || (!with_pairs && path.exists()) {
+            return;
+        }
+
+        let mut value = json!({
+            "total_tokens": stats.total_tokens,
+            "scanned_bytes": stats.scanned_bytes,
+            "token_counts": stats.token_counts,
+            "seq_counts": stats.seq_counts,
+        });
+        let path = if with_pairs {
+            let mut pairs = stats.pair_counts.iter().collect::<Vec<_>>();
+            pairs.sort();
+            value["pair_counts"] = pairs
+                .into_iter()
+                .flat_map(|(&(first, second), &count)| [first as u64, second as u64, count])
+                .collect::<Vec<_>>()
+                .into();
+            pairs_path
+        } else {
+            path
+        };
+
+        let data = value.to_string();
+        let tmp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+        if std::fs::write(&tmp_path, &data).is_err() || std::fs::rename(&tmp_path, &path).is_err() {
+            let _ = std::fs::remove_file(&tmp_path);
+            return;
+        }
+
+        self.cache_dir_bytes += data.len() as u64;
+        if self.cache_dir_bytes > self.max_cache_dir_bytes {
+            self.shrink_cache_dir();
+        }
     }
 
-    fn get_key(token_set: &TokenSet) -> String {
-        let value = token_set.to_json();
-        serde_json::to_string(&value).unwrap()
+    /// Deletes the oldest files of the cache directory until it's down to
+    /// three quarters of its bound.
+    fn shrink_cache_dir(&mut self) {
+        let dir = match &self.cache_dir {
+            Some(dir) => dir,
+            None => return,
+        };
+        let mut files = cache_files(dir);
+        files.sort_by_key(|(_, modified, _)| *modified);
+
+        self.cache_dir_bytes = files.iter().map(|(_, _, len)| len).sum();
+        for (path, _, len) in files {
+            if self.cache_dir_bytes <= self.max_cache_dir_bytes / 4 * 3 {
+                break;
+            }
+            if std::fs::remove_file(path).is_ok() {
+                self.cache_dir_bytes -= len;
+            }
+        }
+    }
+}
+
+/// Whether the file name is one written by `TokenizerCache::save`, i.e. the
+/// hex digest of a key followed by `.json` or `.pairs.json`.
+fn is_cache_file_name(name: &str) -> bool {
+    let digest = name
+        .strip_suffix(".pairs.json")
+        .or_else(|| name.strip_suffix(".json"));
+    digest.is_some_and(|digest| {
+        digest.len() == 32
+            && digest
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    })
+}
+
+/// The stats files in a cache directory with their modification times and
+/// sizes. Other files in the directory are left alone.
+fn cache_files(dir: &Path) -> Vec<(PathBuf, std::time::SystemTime, u64)> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if !is_cache_file_name(path.file_name()?.to_str()?) {
+                return None;
+            }
+            let metadata = std::fs::metadata(&path).ok()?;
+            Some((path, metadata.modified().ok()?, metadata.len()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::memory_sampler::MemorySampler;
+    use crate::processing::Processing;
+
+    #[test]
+    fn paragraph_starts() {
+        assert_eq!(
+            super::paragraph_starts(b"ab\n\ncd\n\n\nef\n"),
+            vec![0, 4, 9]
+        );
+        assert_eq!(super::paragraph_starts(b"\n\nab"), vec![0, 2]);
+    }
+
+    #[test]
+    fn encode_file_in_order() {
+        let mut token_set = TokenSet::new_bytes(Processing::Raw);
+        token_set.add_token("ab".as_bytes());
+        let text = "ab\n\n".repeat(1000);
+        let sampler = MemorySampler::from_str(&text, 40);
+
+        let mut token_ids = Vec::new();
+        let mut paragraph_lengths = Vec::new();
+        encode_file(&token_set, &sampler, |encoded| {
+            token_ids.extend(encoded.token_ids);
+            paragraph_lengths.extend(encoded.paragraph_lengths);
+        });
+
+        let ab = token_set.find_token("ab".as_bytes()).unwrap() as u32;
+        let nl = token_set.find_token("\n".as_bytes()).unwrap() as u32;
+        assert_eq!(token_ids, [ab, nl, nl].repeat(1000));
+        assert_eq!(paragraph_lengths, vec![3; 1000]);
+    }
+
+    fn token_set_with(token: &str) -> TokenSet {
+        let mut token_set = TokenSet::new_bytes(Processing::Raw);
+        token_set.add_token(token.as_bytes());
+        token_set.sort();
+        token_set
+    }
+
+    #[test]
+    fn cache_evicts_least_recently_used() {
+        let sampler = MemorySampler::from_str("abc abd", 1 << 10);
+        let mut cache = TokenizerCache::new(&sampler, None);
+
+        let first = token_set_with("ab");
+        let first_key = cache.key(&first);
+        cache.get_stats(&first);
+        for i in 0..MAX_CACHED_STATS {
+            cache.get_stats(&token_set_with(&format!("x{}", i)));
+            cache.get_stats(&first);
+        }
+        assert_eq!(cache.cache.len(), MAX_CACHED_STATS);
+        assert!(cache.cache.contains_key(&first_key));
+        assert!(!cache.cache.contains_key(&cache.key(&token_set_with("x0"))));
+    }
+
+    #[test]
+    fn cache_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let sampler = MemorySampler::from_str("abc abd", 1 << 10);
+        let token_set = token_set_with("ab");
+
+        let mut cache = TokenizerCache::new(&sampler, Some(100)).with_cache_dir(dir.path());
+        let stats = cache.get_stats(&token_set);
+        assert_eq!(stats.total_tokens, 5);
+
+        let files = std::fs::read_dir(dir.path()).unwrap().collect::<Vec<_>>();
+        assert_eq!(files.len(), 1);
+        let path = files[0].as_ref().unwrap().path();
+        let mut value: Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        value["total_tokens"] = 4.into();
+        std::fs::write(&path, value.to_string()).unwrap();
+
+        // A new cache on the same data reads the stats from the file.
+        let same_data = MemorySampler::from_str("abc abd", 1 << 10);
+        let mut cache = TokenizerCache::new(&same_data, Some(100)).with_cache_dir(dir.path());
+        let loaded = cache.get_stats(&token_set);
+        assert_eq!(loaded.total_tokens, 4);
+        assert_eq!(loaded.token_counts, stats.token_counts);
+        assert_eq!(loaded.initial_size, Some(100));
+
+        let other_data = MemorySampler::from_str("abc abe", 1 << 10);
+        let mut cache = TokenizerCache::new(&other_data, Some(100)).with_cache_dir(dir.path());
+        assert_eq!(cache.get_stats(&token_set).total_tokens, 5);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn cache_dir_pairs_and_bound() {
+        let dir = tempfile::tempdir().unwrap();
+        let sampler = MemorySampler::from_str("abc abd", 1 << 10);
+        let mut cache = TokenizerCache::new(&sampler, None).with_cache_dir(dir.path());
+        let count = |suffix: &str| {
+            std::fs::read_dir(dir.path())
+                .unwrap()
+                .filter(|entry| {
+                    let name = entry.as_ref().unwrap().file_name();
+                    name.to_str().unwrap().ends_with(suffix)
+                })
+                .count()
+        };
+
+        // Only the stats requested with pairs are stored with them.
+        let stats = cache.compute_stats_with_pairs(&token_set_with("ab"), None);
+        cache.insert(&stats);
+        assert_eq!(count(".pairs.json"), 0);
+        cache.get_stats_with_pairs(&token_set_with("bc"));
+        assert_eq!(count(".pairs.json"), 1);
+
+        // Files the cache didn't write are neither counted nor deleted.
+        let unrelated = dir.path().join("tokens300_raw_bytes.json");
+        std::fs::write(&unrelated, "x".repeat(1 << 16)).unwrap();
+        let mut cache = TokenizerCache::new(&sampler, None).with_cache_dir(dir.path());
+
+        cache.max_cache_dir_bytes = 4 * std::fs::metadata(dir.path().join(format!(
+            "{}.json",
+            digest::to_hex(cache.key(&token_set_with("ab")))
+        )))
+        .unwrap()
+        .len();
+        for i in 0..10 {
+            cache.get_stats(&token_set_with(&format!("x{}", i)));
+            assert!(cache.cache_dir_bytes <= cache.max_cache_dir_bytes);
+        }
+        assert!(count(".json") < 5 + 1);
+        assert!(unrelated.exists());
+        assert!(is_cache_file_name(&format!(
+            "{}.pairs.json",
+            "0".repeat(32)
+        )));
+        assert!(!is_cache_file_name(&format!("{}.json", "0".repeat(31))));
     }
 }
@@ -0,0 +1,506 @@
+//! Checkpoints of optimization runs, from which an interrupted run can be
+//! resumed exactly where it stopped.
+//!
+//! A checkpoint directory holds `checkpoint.json` with the current token set,
+//! the state of the optimizer and its trajectory so far. The file is written
+//! to a temporary file first and renamed into place, so that a crash while
+//! saving leaves the previous checkpoint intact.
+
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use super::stats2::TokenStats;
+use super::tokenset::{bytes_to_json, parse_token, Token, TokenSet};
+
+/// The version of the layout of `checkpoint.json`.
+pub const CHECKPOINT_VERSION: u64 = 1;
+
+/// The kinds of changes the optimizer makes to the token set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MoveType {
+    /// The single-byte and ext tokens are chosen again for the same number
+    /// of multi-byte tokens.
+    Bytes,
+    /// A token is added while the token set is smaller than the target.
+    Add,
+    /// A single-byte token is dropped and the merge of the most frequent pair
+    /// is added.
+    BytesAdd,
+    /// A multi-byte token is removed and the best token is added instead.
+    RemoveAdd,
+}
+
+impl MoveType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MoveType::Bytes => "bytes",
+            MoveType::Add => "add",
+            MoveType::BytesAdd => "bytes_add",
+            MoveType::RemoveAdd => "remove_add",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        [
+            MoveType::Bytes,
+            MoveType::Add,
+            MoveType::BytesAdd,
+            MoveType::RemoveAdd,
+        ]
+        .into_iter()
+        .find(|m| m.as_str() == s)
+    }
+}
+
+/// Why an optimization run stopped, recorded in the stats of its result.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// No candidate improves the token set.
+    Converged,
+    /// The bytes per token on the held-out data stopped improving.
+    Patience,
+    MaxTime,
+    MaxSteps,
+    /// A step at the target size saved too small a share of the tokens.
+    MinRelativeGain,
+    /// The run was interrupted by SIGINT.
+    Interrupted,
+}
+
+impl StopReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StopReason::Converged => "converged",
+            StopReason::Patience => "patience",
+            StopReason::MaxTime => "max_time",
+            StopReason::MaxSteps => "max_steps",
+            StopReason::MinRelativeGain => "min_relative_gain",
+            StopReason::Interrupted => "interrupted",
+        }
+    }
+
+    /// Whether the run was cut short, rather than stopped because its steps
+    /// stopped paying off. Runs of several stages end at such a stop.
+    pub fn is_limit(&self) -> bool {
+        matches!(
+            self,
+            StopReason::MaxTime | StopReason::MaxSteps | StopReason::Interrupted
+        )
+    }
+}
+
+/// An accepted step of an optimization run. The trajectory is kept in the
+/// checkpoints and can be logged as JSON lines.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrajectoryStep {
+    pub iteration: u64,
+    pub move_type: MoveType,
+    pub added: Vec<Token>,
+    pub removed: Vec<Token>,
+    /// The number of tokens in the token set after the step.
+    pub ntokens: usize,
+    pub total_tokens_before: u64,
+    pub total_tokens: u64,
+    pub bytes_per_token: f64,
+    /// The number of token sets evaluated to find this one: the removal
+    /// candidates for `RemoveAdd`, 1 otherwise.
+    pub candidates: usize,
+    /// Seconds since the start of the run, including the time before it was
+    /// resumed.
+    pub time: f64,
+    /// Bytes per token on the held-out data, if there is any.
+    pub validation_bytes_per_token: Option<f64>,
+}
+
+impl TrajectoryStep {
+    pub fn new(
+        iteration: u64,
+        move_type: MoveType,
+        before: &TokenStats,
+        after: &TokenStats,
+        candidates: usize,
+        time: f64,
+    ) -> Self {
+        let (removed, added) = tokenset_diff(&before.token_set, &after.token_set);
+        TrajectoryStep {
+            iteration,
+            move_type,
+            added,
+            removed,
+            ntokens: after.token_set.ntokens(),
+            total_tokens_before: before.total_tokens,
+            total_tokens: after.total_tokens,
+            bytes_per_token: after.bytes_per_token(),
+            candidates,
+            time,
+            validation_bytes_per_token: None,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut value = json!({
+            "iteration": self.iteration,
+            "move": self.move_type.as_str(),
+            "added": self.added.iter().map(|t| t.to_json()).collect::<Vec<_>>(),
+            "removed": self.removed.iter().map(|t| t.to_json()).collect::<Vec<_>>(),
+            "ntokens": self.ntokens,
+            "total_tokens_before": self.total_tokens_before,
+            "total_tokens": self.total_tokens,
+            "bytes_per_token": self.bytes_per_token,
+            "candidates": self.candidates,
+            "time": self.time,
+        });
+        if let Some(bytes_per_token) = self.validation_bytes_per_token {
+            value["validation_bytes_per_token"] = bytes_per_token.into();
+        }
+        value
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        let tokens = |field: &str| -> Option<Vec<Token>> {
+            value[field].as_array()?.iter().map(parse_token).collect()
+        };
+        Some(TrajectoryStep {
+            iteration: value["iteration"].as_u64()?,
+            move_type: MoveType::parse(value["move"].as_str()?)?,
+            added: tokens("added")?,
+            removed: tokens("removed")?,
+            ntokens: value["ntokens"].as_u64()? as usize,
+            total_tokens_before: value["total_tokens_before"].as_u64()?,
+            total_tokens: value["total_tokens"].as_u64()?,
+            bytes_per_token: value["bytes_per_token"].as_f64()?,
+            candidates: value["candidates"].as_u64()? as usize,
+            time: value["time"].as_f64()?,
+            validation_bytes_per_token: match &value["validation_bytes_per_token"] {
+                Value::Null => None,
+                v => Some(v.as_f64()?),
+            },
+        })
+    }
+}
+
+/// Returns the tokens of `before` missing in `after` and the tokens of
+/// `after` missing in `before`, both sorted.
+pub fn tokenset_diff(before: &TokenSet, after: &TokenSet) -> (Vec<Token>, Vec<Token>) {
+    let before_set = before.tokens.iter().collect::<HashSet<_>>();
+    let after_set = after.tokens.iter().collect::<HashSet<_>>();
+
+    let mut removed = before_set
+        .difference(&after_set)
+        .map(|&t| t.clone())
+        .collect::<Vec<_>>();
+    removed.sort();
+    let mut added = after_set
+        .difference(&before_set)
+        .map(|&t| t.clone())
+        .collect::<Vec<_>>();
+    added.sort();
+
+    (removed, added)
+}
+
+/// Appends a step to a JSON lines log.
+pub fn log_step(path: &Path, step: &TrajectoryStep) {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .unwrap();
+    writeln!(file, "{}", step.to_json()).unwrap();
+}
+
+/// Drops the steps after `iteration` from the end of a JSON lines log, so
+/// that a run resumed from a checkpoint doesn't log the steps made after the
+/// checkpoint twice. The earlier lines, possibly of other runs, are kept.
+pub fn truncate_log(path: &Path, iteration: u64) -> std::io::Result<()> {
+    let log = match std::fs::read_to_string(path) {
+        Ok(log) => log,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let mut lines = log.lines().collect::<Vec<_>>();
+    let nlines = lines.len();
+    while let Some(line) = lines.last() {
+        let step = serde_json::from_str::<Value>(line).ok();
+        match step.and_then(|step| step["iteration"].as_u64()) {
+            Some(i) if i > iteration => lines.pop(),
+            _ => break,
+        };
+    }
+    if lines.len() < nlines {
+        let kept = lines
+            .iter()
+            .map(|line| format!("{}\n", line))
+            .collect::<String>();
+        std::fs::write(path, kept)?;
+    }
+    Ok(())
+}
+
+/// The token set with the fewest tokens on the held-out data so far.
+#[derive(Clone, Debug)]
+pub struct BestValidation {
+    pub token_set: TokenSet,
+    /// The step after which the token set was found.
+    pub iteration: u64,
+    pub total_tokens: u64,
+    pub bytes_per_token: f64,
+}
+
+impl BestValidation {
+    fn to_json(&self) -> Value {
+        json!({
+            "token_set": self.token_set.to_json(),
+            "iteration": self.iteration,
+            "total_tokens": self.total_tokens,
+            "bytes_per_token": self.bytes_per_token,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        Some(BestValidation {
+            token_set: TokenSet::from_json(value["token_set"].clone()).ok()?,
+            iteration: value["iteration"].as_u64()?,
+            total_tokens: value["total_tokens"].as_u64()?,
+            bytes_per_token: value["bytes_per_token"].as_f64()?,
+        })
+    }
+}
+
+/// Everything besides the token set that the optimizer needs to continue a
+/// run as if it hadn't been interrupted.
+#[derive(Clone, Debug, Default)]
+pub struct OptimizerState {
+    /// How many times the removal of each token was tried.
+    pub removal_count: HashMap<Vec<u8>, usize>,
+    /// The number of accepted steps.
+    pub iteration: u64,
+    /// The sample size of the current stage of
+    /// `Optimizer::optimize_with_increasing_data`.
+    pub sample_size: Option<usize>,
+    /// Seeds the random choices of the run, so that they are repeated when
+    /// it's resumed.
+    pub seed: u64,
+    pub trajectory: Vec<TrajectoryStep>,
+    /// The best token set of the target size on the held-out data, in the
+    /// current stage.
+    pub best_validation: Option<BestValidation>,
+    /// The target size of the current rung of `Optimizer::optimize_ladder`.
+    pub ladder_size: Option<usize>,
+    /// Tokens which mustn't be removed, in the order of their IDs in the
+    /// previous rung of a nested ladder.
+    pub fixed_tokens: Vec<Token>,
+}
+
+impl OptimizerState {
+    /// The time of the run before the last recorded step.
+    pub fn elapsed(&self) -> f64 {
+        self.trajectory.last().map_or(0.0, |step| step.time)
+    }
+}
+
+#[derive(Debug)]
+pub enum CheckpointError {
+    Io(PathBuf, std::io::Error),
+    Format(String),
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheckpointError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            CheckpointError::Format(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for CheckpointError {}
+
+fn checkpoint_path(dir: &Path) -> PathBuf {
+    dir.join("checkpoint.json")
+}
+
+pub fn save_checkpoint(dir: &Path, token_set: &TokenSet, state: &OptimizerState) {
+    let mut removal_count = state.removal_count.iter().collect::<Vec<_>>();
+    removal_count.sort();
+
+    let value = json!({
+        "version": CHECKPOINT_VERSION,
+        "token_set": token_set.to_json(),
+        "iteration": state.iteration,
+        "sample_size": state.sample_size,
+        "seed": state.seed,
+        "removal_count": removal_count
+            .into_iter()
+            .map(|(s, &count)| json!({"token": bytes_to_json(s), "count": count}))
+            .collect::<Vec<_>>(),
+        "trajectory": state.trajectory.iter().map(|s| s.to_json()).collect::<Vec<_>>(),
+        "best_validation": state.best_validation.as_ref().map(|b| b.to_json()),
+        "ladder_size": state.ladder_size,
+        "fixed_tokens": state.fixed_tokens.iter().map(|t| t.to_json()).collect::<Vec<_>>(),
+    });
+
+    std::fs::create_dir_all(dir).unwrap();
+    let path = checkpoint_path(dir);
+    let tmp_path = path.with_extension("json.tmp");
+    std::fs::write(&tmp_path, serde_json::to_string(&value).unwrap()).unwrap();
+    std::fs::rename(&tmp_path, &path).unwrap();
+}
+
+pub fn load_checkpoint(dir: &Path) -> Result<(TokenSet, OptimizerState), CheckpointError> {
+    let path = checkpoint_path(dir);
+    let data = std::fs::read(&path).map_err(|e| CheckpointError::Io(path.clone(), e))?;
+    let value: Value =
+        serde_json::from_slice(&data).map_err(|e| CheckpointError::Format(e.to_string()))?;
+    let invalid = |field: &str| CheckpointError::Format(format!("Invalid field {}", field));
+
+    if value["version"].as_u64() != Some(CHECKPOINT_VERSION) {
+        return Err(invalid("version"));
+    }
+    let token_set = TokenSet::from_json(value["token_set"].clone())
+        .map_err(|e| CheckpointError::Format(e.to_string()))?;
+
+    let mut removal_count = HashMap::new();
+    for entry in value["removal_count"]
+        .as_array()
+        .ok_or(invalid("removal_count"))?
+    {
+        match (parse_token(&entry["token"]), entry["count"].as_u64()) {
+            (Some(Token::Str(s)), Some(count)) => removal_count.insert(s, count as usize),
+            _ => return Err(invalid("removal_count")),
+        };
+    }
+
+    let trajectory = value["trajectory"]
+        .as_array()
+        .ok_or(invalid("trajectory"))?
+        .iter()
+        .map(TrajectoryStep::from_json)
+        .collect::<Option<Vec<_>>>()
+        .ok_or(invalid("trajectory"))?;
+
+    let sample_size = match &value["sample_size"] {
+        Value::Null => None,
+        size => Some(size.as_u64().ok_or(invalid("sample_size"))? as usize),
+    };
+
+    let best_validation = match &value["best_validation"] {
+        Value::Null => None,
+        best => Some(BestValidation::from_json(best).ok_or(invalid("best_validation"))?),
+    };
+
+    // Checkpoints written before ladders existed have neither field.
+    let ladder_size = match &value["ladder_size"] {
+        Value::Null => None,
+        size => Some(size.as_u64().ok_or(invalid("ladder_size"))? as usize),
+    };
+
+    let fixed_tokens = match &value["fixed_tokens"] {
+        Value::Null => Vec::new(),
+        tokens => tokens
+            .as_array()
+            .ok_or(invalid("fixed_tokens"))?
+            .iter()
+            .map(parse_token)
+            .collect::<Option<Vec<_>>>()
+            .ok_or(invalid("fixed_tokens"))?,
+    };
+
+    let state = OptimizerState {
+        removal_count,
+        iteration: value["iteration"].as_u64().ok_or(invalid("iteration"))?,
+        sample_size,
+        seed: value["seed"].as_u64().ok_or(invalid("seed"))?,
+        trajectory,
+        best_validation,
+        ladder_size,
+        fixed_tokens,
+    };
+    Ok((token_set, state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processing::Processing;
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut token_set = TokenSet::new_bytes(Processing::Raw);
+        token_set.add_token(b"ab");
+
+        let mut before = TokenStats::new(TokenSet::new_bytes(Processing::Raw), None);
+        before.total_tokens = 12;
+        let mut stats = TokenStats::new(token_set.clone(), None);
+        stats.total_tokens = 10;
+        stats.scanned_bytes = 25;
+        let mut step = TrajectoryStep::new(7, MoveType::Add, &before, &stats, 1, 1.5);
+        assert_eq!(step.added, vec![Token::Str(b"ab".to_vec())]);
+        assert!(step.removed.is_empty());
+        step.validation_bytes_per_token = Some(2.25);
+        let state = OptimizerState {
+            removal_count: HashMap::from([(b"ab".to_vec(), 2), (vec![0xff, 0xfe], 1)]),
+            iteration: 7,
+            sample_size: Some(1 << 20),
+            seed: 42,
+            trajectory: vec![step],
+            best_validation: Some(BestValidation {
+                token_set: token_set.clone(),
+                iteration: 5,
+                total_tokens: 8,
+                bytes_per_token: 2.25,
+            }),
+            ladder_size: Some(512),
+            fixed_tokens: vec![Token::Str(b"ab".to_vec()), Token::Ext(3)],
+        };
+
+        save_checkpoint(dir.path(), &token_set, &state);
+        let (loaded_set, loaded_state) = load_checkpoint(dir.path()).unwrap();
+        assert_eq!(loaded_set.tokens, token_set.tokens);
+        assert_eq!(loaded_state.removal_count, state.removal_count);
+        assert_eq!(loaded_state.iteration, 7);
+        assert_eq!(loaded_state.sample_size, Some(1 << 20));
+        assert_eq!(loaded_state.seed, 42);
+        assert_eq!(loaded_state.trajectory, state.trajectory);
+        let best = loaded_state.best_validation.as_ref().unwrap();
+        assert_eq!(best.token_set.tokens, token_set.tokens);
+        assert_eq!((best.iteration, best.total_tokens), (5, 8));
+        assert_eq!(loaded_state.elapsed(), 1.5);
+        assert_eq!(loaded_state.trajectory[0].bytes_per_token, 2.5);
+        assert_eq!(loaded_state.trajectory[0].total_tokens_before, 12);
+        assert_eq!(loaded_state.ladder_size, Some(512));
+        assert_eq!(loaded_state.fixed_tokens, state.fixed_tokens);
+
+        let path = checkpoint_path(dir.path());
+        std::fs::write(&path, "{\"version\": 1}").unwrap();
+        assert!(matches!(
+            load_checkpoint(dir.path()),
+            Err(CheckpointError::Format(_))
+        ));
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            load_checkpoint(dir.path()),
+            Err(CheckpointError::Io(..))
+        ));
+    }
+
+    #[test]
+    fn truncate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trajectory.jsonl");
+        truncate_log(&path, 3).unwrap();
+        assert!(!path.exists());
+
+        let log = [1, 2, 3, 4, 5, 1, 2, 3, 4, 5]
+            .map(|i| format!("{{\"iteration\": {}}}\n", i))
+            .concat();
+        std::fs::write(&path, &log).unwrap();
+        truncate_log(&path, 3).unwrap();
+        let truncated = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(truncated, log[..log.len() - 2 * log.len() / 10]);
+    }
+}
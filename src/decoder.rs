@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use super::tokenset::{Token, TokenSet};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The token ID is not part of the token set.
+    UnknownToken { position: usize, token_id: u32 },
+    /// A run of ext tokens that doesn't form any sequence of the token set.
+    DanglingExt { position: usize },
+    /// An ext token following a token that doesn't start any sequence.
+    UnexpectedExt { position: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::UnknownToken { position, token_id } => {
+                write!(f, "unknown token {} at position {}", token_id, position)
+            }
+            DecodeError::DanglingExt { position } => write!(
+                f,
+                "ext tokens at position {} don't form a valid sequence",
+                position
+            ),
+            DecodeError::UnexpectedExt { position } => {
+                write!(f, "unexpected ext token at position {}", position)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Converts sequences of token IDs produced by `FragmentTokenizer::encode`
+/// back into bytes.
+pub struct Decoder {
+    /// Strings of `Str` tokens, `None` for `Ext` tokens.
+    strings: Vec<Option<Vec<u8>>>,
+    /// Token IDs of each multi-token sequence mapped to the encoded string.
+    sequences: HashMap<Vec<u32>, Vec<u8>>,
+    /// For every token, whether it is a `Str` token starting a sequence, as
+    /// in `BytesHuff` token sets.
+    is_prefix: Vec<bool>,
+    /// The length of the longest sequence consisting only of ext tokens, as in
+    /// `Bits*` token sets.
+    max_ext_sequence: usize,
+}
+
+impl Decoder {
+    pub fn new(token_set: &TokenSet) -> Self {
+        let strings = token_set
+            .tokens
+            .iter()
+            .map(|token| match token {
+                Token::Str(s) => Some(s.clone()),
+                Token::Ext(_) => None,
+            })
+            .collect::<Vec<_>>();
+
+        let mut sequences = HashMap::new();
+        let mut is_prefix = vec![false; token_set.ntokens()];
+        let mut max_ext_sequence = 0;
+
+        for seq in token_set.sequences.iter() {
+            let first = seq.tokens[0];
+            if strings[first].is_some() {
+                is_prefix[first] = true;
+            } else {
+                max_ext_sequence = max_ext_sequence.max(seq.tokens.len());
+            }
+            let ids = seq.tokens.iter().map(|&t| t as u32).collect::<Vec<_>>();
+            sequences.insert(ids, seq.string.clone());
+        }
+
+        Decoder {
+            strings,
+            sequences,
+            is_prefix,
+            max_ext_sequence,
+        }
+    }
+
+    pub fn decode(&self, token_ids: &[u32]) -> Result<Vec<u8>, DecodeError> {
+        let mut out = Vec::with_capacity(token_ids.len());
+        self.decode_into(token_ids, &mut out)?;
+        Ok(out)
+    }
+
+    /// Decodes `token_ids` appending the result to `out`.
+    pub fn decode_into(&self, token_ids: &[u32], out: &mut Vec<u8>) -> Result<(), DecodeError> {
+        for (position, &token_id) in token_ids.iter().enumerate() {
+            if token_id as usize >= self.strings.len() {
+                return Err(DecodeError::UnknownToken { position, token_id });
+            }
+        }
+
+        let mut pos = 0;
+
+        while pos < token_ids.len() {
+            let token_id = token_ids[pos] as usize;
+
+            match &self.strings[token_id] {
+                Some(string) => {
+                    let run_end = if self.is_prefix[token_id] {
+                        self.ext_run_end(token_ids, pos + 1)
+                    } else {
+                        pos + 1
+                    };
+
+                    if run_end > pos + 1 {
+                        match self.sequences.get(&token_ids[pos..run_end]) {
+                            Some(string) => out.extend_from_slice(string),
+                            None => return Err(DecodeError::DanglingExt { position: pos }),
+                        }
+                    } else {
+                        out.extend_from_slice(string);
+                    }
+                    pos = run_end;
+                }
+                None => {
+                    if self.max_ext_sequence == 0 {
+                        return Err(DecodeError::UnexpectedExt { position: pos });
+                    }
+                    pos = self.decode_ext_sequence(token_ids, pos, out)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn ext_run_end(&self, token_ids: &[u32], start: usize) -> usize {
+        let mut end = start;
+        while end < token_ids.len() && self.strings[token_ids[end] as usize].is_none() {
+            end += 1;
+        }
+        end
+    }
+
+    /// Decodes the longest sequence of ext tokens starting at `start`. Returns
+    /// the position after it.
+    fn decode_ext_sequence(
+        &self,
+        token_ids: &[u32],
+        start: usize,
+        out: &mut Vec<u8>,
+    ) -> Result<usize, DecodeError> {
+        let limit = token_ids.len().min(start + self.max_ext_sequence);
+        let max_len = self.ext_run_end(&token_ids[..limit], start) - start;
+
+        for len in (1..=max_len).rev() {
+            if let Some(string) = self.sequences.get(&token_ids[start..start + len]) {
+                out.extend_from_slice(string);
+                return Ok(start + len);
+            }
+        }
+
+        Err(DecodeError::DanglingExt { position: start })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optimize_bytes::optimize_bytes_tokenset;
+    use crate::processing::{self, Processing};
+    use crate::tokenizer2::FragmentTokenizer;
+
+    fn check_round_trip(token_set: TokenSet) {
+        let decoder = Decoder::new(&token_set);
+        let tokenizer = FragmentTokenizer::new(token_set);
+        let mut buffer = Vec::new();
+
+        let mut text = "abc abd, Hello\n\nworld".as_bytes().to_vec();
+        text.extend(0..=255);
+
+        let token_ids = tokenizer.encode(&text, &mut buffer);
+        assert_eq!(decoder.decode(&token_ids).unwrap(), text);
+    }
+
+    fn with_tokens(mut token_set: TokenSet) -> TokenSet {
+        for token in ["a", "b", "ab", "abc", " ", "l", "ll"] {
+            if token_set.find_token(token.as_bytes()).is_none() {
+                token_set.add_token(token.as_bytes());
+            }
+        }
+        token_set
+    }
+
+    fn huff_token_set() -> TokenSet {
+        let counts = (0..=255)
+            .map(|i| (i, if i == b' ' || i == b'a' { 100 } else { 1 }))
+            .collect::<Vec<(u8, u64)>>();
+        optimize_bytes_tokenset(&counts, 4, 3, Processing::Raw)
+    }
+
+    #[test]
+    fn round_trip() {
+        check_round_trip(with_tokens(TokenSet::new_bits1(Processing::Raw, true)));
+        check_round_trip(with_tokens(TokenSet::new_bits2(Processing::Raw, true)));
+        check_round_trip(with_tokens(TokenSet::new_bits4(Processing::Raw, true)));
+        check_round_trip(with_tokens(TokenSet::new_bytes(Processing::Raw)));
+        check_round_trip(huff_token_set());
+        check_round_trip(with_tokens(huff_token_set()));
+    }
+
+    #[test]
+    fn malformed() {
+        let mut token_set = TokenSet::new_bits4(Processing::Raw, true);
+        token_set.add_token("a".as_bytes()); // 16
+        let decoder = Decoder::new(&token_set);
+
+        assert_eq!(decoder.decode(&[16, 6, 2, 16]).unwrap(), b"aba");
+        assert_eq!(
+            decoder.decode(&[16, 6, 2, 6]),
+            Err(DecodeError::DanglingExt { position: 3 })
+        );
+        assert_eq!(
+            decoder.decode(&[16, 17]),
+            Err(DecodeError::UnknownToken {
+                position: 1,
+                token_id: 17
+            })
+        );
+
+        let mut token_set = huff_token_set();
+        let non_prefix = token_set.add_token("ab".as_bytes()) as u32;
+        let decoder = Decoder::new(&token_set);
+        let prefix = token_set.sequences[0].tokens[0] as u32;
+
+        assert_eq!(
+            decoder.decode(&[non_prefix, 0]),
+            Err(DecodeError::UnexpectedExt { position: 1 })
+        );
+        assert_eq!(
+            decoder.decode(&[0]),
+            Err(DecodeError::UnexpectedExt { position: 0 })
+        );
+        assert_eq!(
+            decoder.decode(&[prefix, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            Err(DecodeError::DanglingExt { position: 0 })
+        );
+    }
+
+    #[test]
+    fn round_trip_processed() {
+        let text = "Hello \x14World\x16, \x10\x15BYE";
+        let processed = processing::process(text);
+
+        let token_set = with_tokens(TokenSet::new_bits4(Processing::CapsWords, true));
+        let decoder = Decoder::new(&token_set);
+        let tokenizer = FragmentTokenizer::new(token_set);
+        let token_ids = tokenizer.encode(processed.as_bytes(), &mut Vec::new());
+
+        let decoded = String::from_utf8(decoder.decode(&token_ids).unwrap()).unwrap();
+        assert_eq!(processing::unprocess(&decoded), text);
+    }
+}
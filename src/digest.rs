@@ -0,0 +1,81 @@
+//! A stable 128-bit digest (FNV-1a) identifying token sets and data in
+//! caches which outlive the process, so it must not depend on the platform
+//! or on the Rust version like `std::hash::Hasher` implementations may.
+
+const OFFSET_BASIS: u128 = 0x6c62272e07bb014262b821756295c58d;
+const PRIME: u128 = 0x0000000001000000000000000000013b;
+
+#[derive(Clone, Debug)]
+pub struct Digest {
+    state: u128,
+}
+
+impl Default for Digest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Digest {
+    pub fn new() -> Self {
+        Digest {
+            state: OFFSET_BASIS,
+        }
+    }
+
+    /// Feeds raw bytes. Variable-length values should go through
+    /// `write_bytes`, so that the boundaries between them are unambiguous.
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.state ^= b as u128;
+            self.state = self.state.wrapping_mul(PRIME);
+        }
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.write(&value.to_le_bytes());
+    }
+
+    /// Feeds the length of `bytes` followed by the bytes.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u64(bytes.len() as u64);
+        self.write(bytes);
+    }
+
+    pub fn finish(&self) -> u128 {
+        self.state
+    }
+}
+
+/// Formats a digest as 32 lowercase hexadecimal digits.
+pub fn to_hex(digest: u128) -> String {
+    format!("{:032x}", digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_values() {
+        assert_eq!(
+            to_hex(Digest::new().finish()),
+            "6c62272e07bb014262b821756295c58d"
+        );
+
+        let mut digest = Digest::new();
+        digest.write(b"a");
+        assert_eq!(to_hex(digest.finish()), "d228cb696f1a8caf78912b704e4a8964");
+    }
+
+    #[test]
+    fn length_prefix() {
+        let mut first = Digest::new();
+        first.write_bytes(b"ab");
+        first.write_bytes(b"c");
+        let mut second = Digest::new();
+        second.write_bytes(b"a");
+        second.write_bytes(b"bc");
+        assert_ne!(first.finish(), second.finish());
+    }
+}
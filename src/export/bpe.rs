@@ -0,0 +1,129 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::ops::Range;
+
+/// Splits `bytes` the way merge-based BPE tokenizers do: starting from single
+/// bytes, repeatedly merges the adjacent pair of pieces with the lowest rank
+/// given by `rank`, the leftmost one in case of a tie, until no pair can be
+/// merged. `rank` gets the merged piece and the length of its left part.
+/// Returns the ranges of the pieces.
+pub fn merge_encode<F>(bytes: &[u8], rank: F) -> Vec<Range<usize>>
+where
+    F: Fn(&[u8], usize) -> Option<usize>,
+{
+    let n = bytes.len();
+    // The pieces are identified by their starts. next[i] is the start of the
+    // piece after the one starting at i, prev[i] the start of the one before.
+    let mut next = (1..=n).collect::<Vec<_>>();
+    let mut prev = (0..n).map(|i| i.wrapping_sub(1)).collect::<Vec<_>>();
+    let mut alive = vec![true; n];
+    let pair_rank = |next: &[usize], i: usize| {
+        let j = next[i];
+        if j < n {
+            rank(&bytes[i..next[j]], j - i)
+        } else {
+            None
+        }
+    };
+
+    // The candidate merges by rank, then by position. An entry is outdated if
+    // the piece is gone or its right neighbour has changed the rank.
+    let mut heap = (0..n)
+        .filter_map(|i| pair_rank(&next, i).map(|r| Reverse((r, i))))
+        .collect::<BinaryHeap<_>>();
+
+    while let Some(Reverse((r, i))) = heap.pop() {
+        if !alive[i] || pair_rank(&next, i) != Some(r) {
+            continue;
+        }
+
+        let j = next[i];
+        alive[j] = false;
+        next[i] = next[j];
+        if next[i] < n {
+            prev[next[i]] = i;
+        }
+
+        if let Some(r) = pair_rank(&next, i) {
+            heap.push(Reverse((r, i)));
+        }
+        if i > 0 {
+            if let Some(r) = pair_rank(&next, prev[i]) {
+                heap.push(Reverse((r, prev[i])));
+            }
+        }
+    }
+
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    while start < n {
+        pieces.push(start..next[start]);
+        start = next[start];
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn merge() {
+        let ranks = HashMap::from([
+            ((b"a".as_slice(), b"b".as_slice()), 1),
+            ((b"b", b"c"), 0),
+            ((b"a", b"bc"), 2),
+            ((b"c", b"c"), 3),
+        ]);
+        let rank = |s: &[u8], mid| ranks.get(&s.split_at(mid)).copied();
+
+        assert_eq!(merge_encode(b"abc", rank), vec![0..3]);
+        assert_eq!(merge_encode(b"abab", rank), vec![0..2, 2..4]);
+        assert_eq!(merge_encode(b"ccc", rank), vec![0..2, 2..3]);
+        assert_eq!(merge_encode(b"", rank), vec![]);
+    }
+
+    /// Merges the pair with the lowest rank found by a linear scan.
+    fn merge_encode_naive<F>(bytes: &[u8], rank: F) -> Vec<Range<usize>>
+    where
+        F: Fn(&[u8], usize) -> Option<usize>,
+    {
+        let mut starts = (0..=bytes.len()).collect::<Vec<_>>();
+        loop {
+            let best = (0..starts.len().saturating_sub(2))
+                .filter_map(|i| {
+                    rank(&bytes[starts[i]..starts[i + 2]], starts[i + 1] - starts[i])
+                        .map(|r| (r, i))
+                })
+                .min();
+            match best {
+                Some((_, i)) => starts.remove(i + 1),
+                None => break,
+            };
+        }
+        starts.windows(2).map(|w| w[0]..w[1]).collect()
+    }
+
+    proptest! {
+        #[test]
+        fn matches_naive(
+            merges in prop::collection::vec(("[abc]{1,3}", "[abc]{1,3}"), 0..20),
+            text in "[abcd]{0,40}",
+        ) {
+            let ranks = merges
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(r, (left, right))| ((left.as_bytes(), right.as_bytes()), r))
+                .collect::<HashMap<_, _>>();
+            let rank = |s: &[u8], mid| ranks.get(&s.split_at(mid)).copied();
+
+            prop_assert_eq!(
+                merge_encode(text.as_bytes(), rank),
+                merge_encode_naive(text.as_bytes(), rank)
+            );
+        }
+    }
+}
@@ -0,0 +1,227 @@
+//! Export of token sets to the `tokenizer.json` format of HuggingFace
+//! tokenizers, as a byte-level BPE model.
+//!
+//! The merges are derived so that every token which can be split into two
+//! other tokens is produced by BPE from its own string. The exported
+//! tokenizer is still not equivalent to `FragmentTokenizer`: BPE chooses
+//! merges greedily, so on longer texts its segmentation can differ from the
+//! optimal one. The things that can't be expressed at all are reported as
+//! `ExportIssue`s.
+
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+use super::bpe::merge_encode;
+use super::{common_issues, ExportIssue};
+use crate::tokenset::{Token, TokenSet};
+
+/// Maps bytes to the printable characters used by byte-level BPE models, as
+/// in GPT-2.
+pub fn byte_level_alphabet() -> [char; 256] {
+    let mut alphabet = ['\0'; 256];
+    let mut n = 0;
+    for b in 0..=255u8 {
+        let printable = matches!(b, b'!'..=b'~' | 0xa1..=0xac | 0xae..=0xff);
+        alphabet[b as usize] = if printable {
+            b as char
+        } else {
+            n += 1;
+            char::from_u32(255 + n).unwrap()
+        };
+    }
+    alphabet
+}
+
+fn byte_level(bytes: &[u8], alphabet: &[char; 256]) -> String {
+    bytes.iter().map(|&b| alphabet[b as usize]).collect()
+}
+
+/// The name of the added token standing for an ext token.
+fn ext_token_name(x: u8) -> String {
+    format!("<|ext_{}|>", x)
+}
+
+/// Derives the merges producing the tokens of the token set, shortest tokens
+/// first. Returns the merges in the order of their ranks, and the tokens that
+/// can't be produced.
+fn derive_merges(token_set: &TokenSet) -> (Vec<(usize, usize)>, Vec<ExportIssue>) {
+    let mut ids = HashMap::new();
+    for (id, token) in token_set.tokens.iter().enumerate() {
+        if let Token::Str(s) = token {
+            ids.insert(s.as_slice(), id);
+        }
+    }
+
+    let mut order = ids
+        .iter()
+        .filter(|(s, _)| s.len() > 1)
+        .map(|(&s, &id)| (s, id))
+        .collect::<Vec<_>>();
+    order.sort_by_key(|&(s, id)| (s.len(), id));
+
+    let mut ranks: HashMap<(usize, usize), usize> = HashMap::new();
+    let mut merges = Vec::new();
+    let mut unreachable = Vec::new();
+
+    for (string, id) in order {
+        let pieces = merge_encode(string, |s, mid| {
+            let (a, b) = s.split_at(mid);
+            ranks.get(&(*ids.get(a)?, *ids.get(b)?)).copied()
+        });
+
+        let pair = if pieces.len() == 2 {
+            ids.get(&string[pieces[0].clone()])
+                .zip(ids.get(&string[pieces[1].clone()]))
+        } else {
+            None
+        };
+
+        match pair {
+            Some((&a, &b)) => {
+                ranks.insert((a, b), merges.len());
+                merges.push((a, b));
+            }
+            None => {
+                unreachable.push((id, pieces.into_iter().map(|r| string[r].to_vec()).collect()))
+            }
+        }
+    }
+
+    unreachable.sort();
+    let issues = unreachable
+        .into_iter()
+        .map(|(token, pieces)| ExportIssue::UnreachableToken { token, pieces })
+        .collect();
+    (merges, issues)
+}
+
+/// Converts the token set to a HuggingFace `tokenizer.json` with the same
+/// token IDs. Ext tokens become special added tokens, which the model never
+/// produces.
+pub fn to_tokenizer_json(token_set: &TokenSet) -> (Value, Vec<ExportIssue>) {
+    let alphabet = byte_level_alphabet();
+    let mut issues = common_issues(token_set);
+
+    let mut vocab = Map::new();
+    let mut added_tokens = Vec::new();
+    let mut names = Vec::new();
+
+    for (id, token) in token_set.tokens.iter().enumerate() {
+        let name = match token {
+            Token::Str(s) => byte_level(s, &alphabet),
+            &Token::Ext(x) => {
+                added_tokens.push(json!({
+                    "id": id,
+                    "content": ext_token_name(x),
+                    "single_word": false,
+                    "lstrip": false,
+                    "rstrip": false,
+                    "normalized": false,
+                    "special": true,
+                }));
+                ext_token_name(x)
+            }
+        };
+        vocab.insert(name.clone(), id.into());
+        names.push(name);
+    }
+
+    let (merges, merge_issues) = derive_merges(token_set);
+    issues.extend(merge_issues);
+
+    let merges = merges
+        .into_iter()
+        .map(|(a, b)| format!("{} {}", names[a], names[b]))
+        .collect::<Vec<_>>();
+
+    let byte_level_config = json!({
+        "type": "ByteLevel",
+        "add_prefix_space": false,
+        "trim_offsets": false,
+        "use_regex": false,
+    });
+
+    let tokenizer = json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": added_tokens,
+        "normalizer": null,
+        "pre_tokenizer": byte_level_config,
+        "post_processor": null,
+        "decoder": byte_level_config,
+        "model": {
+            "type": "BPE",
+            "dropout": null,
+            "unk_token": null,
+            "continuing_subword_prefix": null,
+            "end_of_word_suffix": null,
+            "fuse_unk": false,
+            "byte_fallback": false,
+            "vocab": vocab,
+            "merges": merges,
+        },
+    });
+
+    (tokenizer, issues)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processing::Processing;
+    use std::collections::HashSet;
+
+    #[test]
+    fn alphabet() {
+        let alphabet = byte_level_alphabet();
+        assert_eq!(alphabet[b'a' as usize], 'a');
+        assert_eq!(alphabet[b' ' as usize], 'Ġ');
+        assert_eq!(alphabet[b'\n' as usize], 'Ċ');
+        assert_eq!(alphabet[0xad], 'Ń');
+        assert_eq!(alphabet.iter().collect::<HashSet<_>>().len(), 256);
+    }
+
+    #[test]
+    fn bytes() {
+        let mut token_set = TokenSet::new_bytes(Processing::Raw);
+        let the = token_set.add_token(b" the");
+        token_set.add_token(b" t");
+        token_set.add_token(b"he");
+        token_set.add_token(b"xyz");
+
+        let (tokenizer, issues) = to_tokenizer_json(&token_set);
+        assert_eq!(
+            issues,
+            vec![ExportIssue::UnreachableToken {
+                token: 259,
+                pieces: vec![b"x".to_vec(), b"y".to_vec(), b"z".to_vec()]
+            }]
+        );
+
+        let model = &tokenizer["model"];
+        assert_eq!(model["vocab"]["Ġthe"], the);
+        assert_eq!(model["merges"], json!(["Ġ t", "h e", "Ġt he"]));
+        assert_eq!(tokenizer["added_tokens"], json!([]));
+    }
+
+    #[test]
+    fn ext_tokens() {
+        let mut token_set = TokenSet::new_bits4(Processing::CapsWords, true);
+        token_set.add_token(b"a");
+        token_set.add_token(b"b");
+        token_set.add_token(b"ab");
+
+        let (tokenizer, issues) = to_tokenizer_json(&token_set);
+        assert_eq!(issues[0], ExportIssue::Processing(Processing::CapsWords));
+        match &issues[1] {
+            ExportIssue::ExtSequences(bytes) => assert_eq!(bytes.len(), 254),
+            issue => panic!("unexpected issue {}", issue),
+        }
+        assert_eq!(issues.len(), 2);
+
+        assert_eq!(tokenizer["added_tokens"].as_array().unwrap().len(), 16);
+        assert_eq!(tokenizer["model"]["vocab"]["<|ext_15|>"], 15);
+        assert_eq!(tokenizer["model"]["merges"], json!(["a b"]));
+    }
+}
@@ -0,0 +1,80 @@
+//! Export of token sets to the formats of other tokenizer libraries.
+
+use std::fmt;
+
+use crate::processing::Processing;
+use crate::tokenset::{show_bytes, Token, TokenSet};
+
+mod bpe;
+pub mod huggingface;
+pub mod tiktoken;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExportIssue {
+    /// The processing has no counterpart in the exported tokenizer.
+    Processing(Processing),
+    /// These bytes don't have tokens of their own and are encoded by sequences
+    /// of ext tokens, which merge-based tokenizers can't express.
+    ExtSequences(Vec<u8>),
+    /// No merge produces the token from its own string, which is encoded as
+    /// `pieces` instead.
+    UnreachableToken { token: usize, pieces: Vec<Vec<u8>> },
+}
+
+impl fmt::Display for ExportIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExportIssue::Processing(processing) => write!(
+                f,
+                "{} processing isn't supported by the exported tokenizer: the text has \
+                 to be processed before tokenization and unprocessed after decoding",
+                processing
+            ),
+            ExportIssue::ExtSequences(bytes) => write!(
+                f,
+                "{} bytes are encoded by sequences of ext tokens and can't be encoded \
+                 by the exported tokenizer: {}",
+                bytes.len(),
+                show_bytes(bytes)
+            ),
+            ExportIssue::UnreachableToken { token, pieces } => {
+                write!(
+                    f,
+                    "token {} is never produced by merges, it's encoded as",
+                    token
+                )?;
+                for piece in pieces.iter() {
+                    write!(f, " {}", show_bytes(piece))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The issues that apply to all merge-based formats: the processing and the
+/// bytes without tokens of their own.
+fn common_issues(token_set: &TokenSet) -> Vec<ExportIssue> {
+    let mut issues = Vec::new();
+
+    if token_set.processing != Processing::Raw {
+        issues.push(ExportIssue::Processing(token_set.processing));
+    }
+
+    let mut has_token = [false; 256];
+    for token in token_set.tokens.iter() {
+        if let Token::Str(s) = token {
+            if s.len() == 1 {
+                has_token[s[0] as usize] = true;
+            }
+        }
+    }
+    let missing = (0..=255u8)
+        .filter(|&b| !has_token[b as usize])
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        issues.push(ExportIssue::ExtSequences(missing));
+    }
+
+    issues
+}
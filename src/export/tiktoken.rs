@@ -0,0 +1,245 @@
+//! Export of token sets to the rank files of tiktoken, with one line per token
+//! holding its base64-encoded bytes and its rank.
+//!
+//! tiktoken encodes text by repeatedly merging the adjacent pieces whose
+//! concatenation has the lowest rank, so the ranks are both the token IDs and
+//! the merge priorities. `check_ranks` measures how often this greedy encoding
+//! differs from the optimal one of `FragmentTokenizer`.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+
+use super::bpe::merge_encode;
+use super::{common_issues, ExportIssue};
+use crate::batch_tokenize::paragraph_starts;
+use crate::input::sample::Sampler;
+use crate::stats2::TokenStats;
+use crate::tokenizer2::FragmentTokenizer;
+use crate::tokenset::{Token, TokenSet};
+
+/// Orders the `Str` tokens by their counts, the most frequent first. The
+/// position of a token ID in the result is its rank.
+pub fn rank_tokens(stats: &TokenStats) -> Vec<usize> {
+    let mut ranked = stats
+        .token_set
+        .tokens
+        .iter()
+        .enumerate()
+        .filter(|(_, token)| matches!(token, Token::Str(_)))
+        .map(|(id, _)| id)
+        .collect::<Vec<_>>();
+    ranked.sort_by_key(|&id| (Reverse(stats.token_counts[id]), id));
+    ranked
+}
+
+fn token_bytes(token_set: &TokenSet, id: usize) -> &[u8] {
+    match &token_set.tokens[id] {
+        Token::Str(s) => s,
+        Token::Ext(_) => panic!("Ext token {} can't be ranked", id),
+    }
+}
+
+/// Writes the ranked tokens in the tiktoken format.
+pub fn write_ranks<W: Write>(
+    token_set: &TokenSet,
+    ranked: &[usize],
+    out: &mut W,
+) -> io::Result<()> {
+    for (rank, &id) in ranked.iter().enumerate() {
+        writeln!(
+            out,
+            "{} {}",
+            STANDARD.encode(token_bytes(token_set, id)),
+            rank
+        )?;
+    }
+    Ok(())
+}
+
+/// The greedy rank-merge encoder of tiktoken.
+pub struct RankEncoder {
+    ranks: HashMap<Vec<u8>, usize>,
+}
+
+impl RankEncoder {
+    pub fn new(token_set: &TokenSet, ranked: &[usize]) -> Self {
+        let ranks = ranked
+            .iter()
+            .enumerate()
+            .map(|(rank, &id)| (token_bytes(token_set, id).to_vec(), rank))
+            .collect();
+        RankEncoder { ranks }
+    }
+
+    /// Returns the ranks of the pieces of `bytes`, `None` for the bytes that
+    /// don't have tokens.
+    pub fn encode(&self, bytes: &[u8]) -> Vec<Option<usize>> {
+        merge_encode(bytes, |s, _| self.ranks.get(s).copied())
+            .into_iter()
+            .map(|r| self.ranks.get(&bytes[r]).copied())
+            .collect()
+    }
+}
+
+/// Reports what the rank file can't reproduce, including the tokens that
+/// aren't produced by merges from their own strings.
+pub fn export_issues(token_set: &TokenSet, ranked: &[usize]) -> Vec<ExportIssue> {
+    let mut issues = common_issues(token_set);
+    let encoder = RankEncoder::new(token_set, ranked);
+
+    let mut ids = ranked.to_vec();
+    ids.sort();
+    for id in ids {
+        let string = token_bytes(token_set, id);
+        let pieces = merge_encode(string, |s, _| encoder.ranks.get(s).copied());
+        if pieces.len() > 1 {
+            issues.push(ExportIssue::UnreachableToken {
+                token: id,
+                pieces: pieces.into_iter().map(|r| string[r].to_vec()).collect(),
+            });
+        }
+    }
+
+    issues
+}
+
+/// The comparison of the greedy rank-merge encoding with the optimal one.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RankCheck {
+    pub paragraphs: u64,
+    /// Paragraphs for which the tokens differ.
+    pub differing_paragraphs: u64,
+    pub optimal_tokens: u64,
+    pub merge_tokens: u64,
+}
+
+impl fmt::Display for RankCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.optimal_tokens == 0 {
+            return write!(f, "There is no data to check.");
+        }
+        write!(
+            f,
+            "{} of {} paragraphs ({:.2}%) are tokenized differently. Rank merging \
+             gives {} tokens instead of the optimal {} ({:+.2}%).",
+            self.differing_paragraphs,
+            self.paragraphs,
+            100.0 * self.differing_paragraphs as f64 / self.paragraphs as f64,
+            self.merge_tokens,
+            self.optimal_tokens,
+            100.0 * (self.merge_tokens as f64 / self.optimal_tokens as f64 - 1.0)
+        )
+    }
+}
+
+/// Encodes each paragraph of the data both with the rank file and with
+/// `FragmentTokenizer`, and counts the differences.
+pub fn check_ranks<'a, S: Sampler<'a>>(
+    token_set: &TokenSet,
+    ranked: &[usize],
+    sampler: &'a S,
+) -> RankCheck {
+    let encoder = RankEncoder::new(token_set, ranked);
+    let tokenizer = FragmentTokenizer::new(token_set.clone());
+
+    let mut rank_of_id = vec![None; token_set.ntokens()];
+    for (rank, &id) in ranked.iter().enumerate() {
+        rank_of_id[id] = Some(rank);
+    }
+
+    let mut check = RankCheck::default();
+    let mut buffer = Vec::new();
+
+    for sample in sampler.iter() {
+        let bytes = sample.as_bytes();
+        let starts = paragraph_starts(bytes);
+
+        for (i, &start) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).copied().unwrap_or(bytes.len());
+            let paragraph = &bytes[start..end];
+
+            let optimal = tokenizer
+                .encode(paragraph, &mut buffer)
+                .into_iter()
+                .map(|id| rank_of_id[id as usize])
+                .collect::<Vec<_>>();
+            let merged = encoder.encode(paragraph);
+
+            check.paragraphs += 1;
+            check.optimal_tokens += optimal.len() as u64;
+            check.merge_tokens += merged.len() as u64;
+            if optimal != merged {
+                check.differing_paragraphs += 1;
+            }
+        }
+    }
+
+    check
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::memory_sampler::MemorySampler;
+    use crate::processing::Processing;
+
+    fn ranked_token_set() -> (TokenSet, Vec<usize>) {
+        let mut token_set = TokenSet::new_bytes(Processing::Raw);
+        let ab = token_set.add_token(b"ab");
+        let bc = token_set.add_token(b"bc");
+        let bcd = token_set.add_token(b"bcd");
+
+        let mut stats = TokenStats::new(token_set.clone(), None);
+        stats.token_counts[ab] = 10;
+        stats.token_counts[bc] = 5;
+        stats.token_counts[bcd] = 3;
+        stats.token_counts[b'x' as usize] = 3;
+
+        let ranked = rank_tokens(&stats);
+        assert_eq!(&ranked[..5], [ab, bc, b'x' as usize, bcd, 0]);
+        (token_set, ranked)
+    }
+
+    #[test]
+    fn write() {
+        let (token_set, ranked) = ranked_token_set();
+        let mut out = Vec::new();
+        write_ranks(&token_set, &ranked, &mut out).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 259);
+        assert_eq!(&lines[..3], ["YWI= 0", "YmM= 1", "eA== 2"]);
+
+        assert!(export_issues(&token_set, &ranked).is_empty());
+    }
+
+    #[test]
+    fn check() {
+        let (token_set, ranked) = ranked_token_set();
+        let encoder = RankEncoder::new(&token_set, &ranked);
+        assert_eq!(
+            encoder.encode(b"abcd"),
+            [Some(0), Some(4 + 99), Some(4 + 100)]
+        );
+
+        let sampler = MemorySampler::from_str("abcd\n\nbc\n\n", 1 << 10);
+        assert_eq!(
+            check_ranks(&token_set, &ranked, &sampler),
+            RankCheck {
+                paragraphs: 2,
+                differing_paragraphs: 1,
+                optimal_tokens: 7,
+                merge_tokens: 8,
+            }
+        );
+
+        let empty = MemorySampler::from_str("", 1 << 10);
+        let check = check_ranks(&token_set, &ranked, &empty);
+        assert_eq!(check.to_string(), "There is no data to check.");
+    }
+}
@@ -0,0 +1,131 @@
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+use super::{piece_to_bytes, ImportError, ParsedVocabulary};
+use crate::export::huggingface::byte_level_alphabet;
+
+/// Whether the component, possibly a `Sequence` of components, includes a
+/// `ByteLevel` one.
+fn has_byte_level(value: &Value) -> bool {
+    match value["type"].as_str() {
+        Some("ByteLevel") => true,
+        Some("Sequence") => ["pretokenizers", "decoders"]
+            .iter()
+            .filter_map(|key| value[key].as_array())
+            .flatten()
+            .any(has_byte_level),
+        _ => false,
+    }
+}
+
+/// Parses a HuggingFace `tokenizer.json` with a BPE model. Returns the tokens
+/// ordered by ID. Byte-level vocabularies are mapped back to bytes, the others
+/// are read as SentencePiece pieces. Special added tokens are skipped.
+pub fn parse(contents: &str) -> Result<ParsedVocabulary, ImportError> {
+    let value: Value =
+        serde_json::from_str(contents).map_err(|e| ImportError::Format(e.to_string()))?;
+
+    let model = &value["model"];
+    if model["type"] != "BPE" {
+        return Err(ImportError::Format(format!(
+            "expected a BPE model, found {}",
+            model["type"]
+        )));
+    }
+    let vocab = model["vocab"]
+        .as_object()
+        .ok_or_else(|| ImportError::Format("$.model.vocab: expected an object".to_string()))?;
+
+    let special = value["added_tokens"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|t| t["special"] == true)
+        .filter_map(|t| t["content"].as_str())
+        .collect::<HashSet<_>>();
+
+    let byte_level = has_byte_level(&value["pre_tokenizer"]) || has_byte_level(&value["decoder"]);
+    let byte_of_char = byte_level_alphabet()
+        .iter()
+        .enumerate()
+        .map(|(b, &c)| (c, b as u8))
+        .collect::<HashMap<_, _>>();
+
+    let mut entries = Vec::new();
+    for (string, id) in vocab.iter() {
+        let id = id.as_u64().ok_or_else(|| {
+            ImportError::Format(format!("$.model.vocab: invalid ID of {:?}", string))
+        })?;
+        entries.push((id, string.as_str()));
+    }
+    entries.sort();
+
+    let mut tokens = Vec::new();
+    let mut skipped = Vec::new();
+    for (_, string) in entries {
+        let bytes = if special.contains(string) {
+            None
+        } else if byte_level {
+            string
+                .chars()
+                .map(|c| byte_of_char.get(&c).copied())
+                .collect()
+        } else {
+            Some(piece_to_bytes(string))
+        };
+
+        if bytes.is_none() {
+            skipped.push(string.to_string());
+        }
+        tokens.push(bytes);
+    }
+
+    Ok((tokens, skipped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn byte_level() {
+        let value = json!({
+            "added_tokens": [{"id": 3, "content": "<|endoftext|>", "special": true}],
+            "pre_tokenizer": {
+                "type": "Sequence",
+                "pretokenizers": [{"type": "Split"}, {"type": "ByteLevel"}],
+            },
+            "model": {
+                "type": "BPE",
+                "vocab": {"Ġthe": 1, "a": 0, "Ċ": 2, "<|endoftext|>": 3, "ы": 4},
+                "merges": [],
+            },
+        });
+        let (tokens, skipped) = parse(&value.to_string()).unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Some(b"a".to_vec()),
+                Some(b" the".to_vec()),
+                Some(b"\n".to_vec()),
+                None,
+                None
+            ]
+        );
+        assert_eq!(skipped, vec!["<|endoftext|>", "ы"]);
+    }
+
+    #[test]
+    fn metaspace() {
+        let value = json!({
+            "decoder": {"type": "Sequence", "decoders": [{"type": "ByteFallback"}]},
+            "model": {"type": "BPE", "vocab": {"▁the": 0, "<0x0A>": 1}},
+        });
+        let (tokens, _) = parse(&value.to_string()).unwrap();
+        assert_eq!(tokens, vec![Some(b" the".to_vec()), Some(b"\n".to_vec())]);
+
+        let value = json!({"model": {"type": "WordPiece", "vocab": {}}});
+        assert!(parse(&value.to_string()).is_err());
+    }
+}
@@ -0,0 +1,181 @@
+//! Import of the vocabularies of other tokenizers as `Bytes` token sets, to
+//! compare them with ours on the same data.
+//!
+//! Only the vocabulary is imported. `FragmentTokenizer` segments text
+//! optimally with it, so the token counts are those of the optimal
+//! segmentation, not of the original merge-based tokenizer.
+
+use clap::ValueEnum;
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::processing::Processing;
+use crate::tokenset::{TokenSet, TokenType};
+
+mod huggingface;
+mod sentencepiece;
+mod tiktoken;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum VocabFormat {
+    /// HuggingFace `tokenizer.json` with a BPE model.
+    #[value(name = "huggingface")]
+    HuggingFace,
+    /// tiktoken rank file.
+    Tiktoken,
+    /// SentencePiece vocabulary dump, with a piece and a score on each line.
+    #[value(name = "sentencepiece")]
+    SentencePiece,
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    Io(std::io::Error),
+    Format(String),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImportError::Io(e) => write!(f, "{}", e),
+            ImportError::Format(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+impl From<std::io::Error> for ImportError {
+    fn from(e: std::io::Error) -> Self {
+        ImportError::Io(e)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// The number of tokens in the imported vocabulary.
+    pub vocab_size: usize,
+    /// Tokens that don't stand for text, such as special or control tokens.
+    pub skipped: Vec<String>,
+    /// Tokens with the same bytes as an earlier token.
+    pub duplicates: usize,
+    /// Single bytes missing from the vocabulary, added so that any text can
+    /// be encoded.
+    pub added_bytes: usize,
+}
+
+impl fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} tokens in the vocabulary, {} skipped, {} duplicates, {} single bytes added.",
+            self.vocab_size,
+            self.skipped.len(),
+            self.duplicates,
+            self.added_bytes
+        )?;
+        if !self.skipped.is_empty() {
+            write!(f, " Skipped: {}", self.skipped.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+/// The bytes of the tokens of a vocabulary in the order of their IDs, `None`
+/// for the skipped tokens, and the names of the skipped tokens.
+type ParsedVocabulary = (Vec<Option<Vec<u8>>>, Vec<String>);
+
+/// Builds a token set from the bytes of the imported tokens, `None` for the
+/// skipped ones, which are listed in `skipped`. Empty tokens are skipped too.
+fn build_token_set(tokens: Vec<Option<Vec<u8>>>, skipped: Vec<String>) -> (TokenSet, ImportReport) {
+    let mut token_set = TokenSet::new(0, Processing::Raw, TokenType::Bytes, false);
+    let mut report = ImportReport {
+        vocab_size: tokens.len(),
+        skipped,
+        ..Default::default()
+    };
+
+    let mut seen = HashSet::new();
+    for token in tokens.into_iter().flatten() {
+        if token.is_empty() {
+            report.skipped.push("\"\"".to_string());
+        } else if seen.contains(&token) {
+            report.duplicates += 1;
+        } else {
+            token_set.add_token(&token);
+            seen.insert(token);
+        }
+    }
+
+    for b in 0..=255u8 {
+        if !seen.contains([b].as_slice()) {
+            token_set.add_token(&[b]);
+            report.added_bytes += 1;
+        }
+    }
+
+    (token_set, report)
+}
+
+/// Converts a SentencePiece piece to bytes: `<0xNN>` stands for a single
+/// byte, and `▁` for a space.
+fn piece_to_bytes(piece: &str) -> Vec<u8> {
+    if piece.len() == 6 && piece.starts_with("<0x") && piece.ends_with('>') {
+        if let Ok(b) = u8::from_str_radix(&piece[3..5], 16) {
+            return vec![b];
+        }
+    }
+    piece.replace('\u{2581}', " ").into_bytes()
+}
+
+/// Reads a vocabulary in the given format as a token set.
+pub fn read_vocabulary(
+    format: VocabFormat,
+    filename: &str,
+) -> Result<(TokenSet, ImportReport), ImportError> {
+    let contents = std::fs::read_to_string(filename)?;
+    let (tokens, skipped) = match format {
+        VocabFormat::HuggingFace => huggingface::parse(&contents)?,
+        VocabFormat::Tiktoken => tiktoken::parse(&contents)?,
+        VocabFormat::SentencePiece => sentencepiece::parse(&contents)?,
+    };
+    Ok(build_token_set(tokens, skipped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenset::Token;
+
+    #[test]
+    fn pieces() {
+        assert_eq!(piece_to_bytes("\u{2581}the"), b" the");
+        assert_eq!(piece_to_bytes("<0x0A>"), b"\n");
+        assert_eq!(piece_to_bytes("<0xZZ>"), b"<0xZZ>");
+    }
+
+    #[test]
+    fn build() {
+        let tokens = vec![
+            Some(b"a".to_vec()),
+            None,
+            Some(b"the".to_vec()),
+            Some(b"a".to_vec()),
+            Some(Vec::new()),
+        ];
+        let (token_set, report) = build_token_set(tokens, vec!["<s>".to_string()]);
+
+        assert_eq!(token_set.ntokens(), 256 + 1);
+        assert_eq!(token_set.tokens[1], Token::Str(b"the".to_vec()));
+        assert!(token_set.validate().is_empty());
+        assert_eq!(
+            report,
+            ImportReport {
+                vocab_size: 5,
+                skipped: vec!["<s>".to_string(), "\"\"".to_string()],
+                duplicates: 1,
+                added_bytes: 255,
+            }
+        );
+    }
+}
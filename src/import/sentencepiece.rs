@@ -0,0 +1,52 @@
+use super::{piece_to_bytes, ImportError, ParsedVocabulary};
+
+/// The unknown and control pieces that SentencePiece adds by default.
+const CONTROL_PIECES: [&str; 4] = ["<unk>", "<s>", "</s>", "<pad>"];
+
+/// Parses a SentencePiece vocabulary dump with a piece and its score,
+/// separated by a tab, on each line. The dump doesn't record the types of
+/// the pieces, so the control pieces are recognized by their default names
+/// and zero scores. Other pieces, such as user-defined `<br>`, are kept.
+pub fn parse(contents: &str) -> Result<ParsedVocabulary, ImportError> {
+    let mut tokens = Vec::new();
+    let mut skipped = Vec::new();
+
+    for (i, line) in contents.lines().enumerate() {
+        let invalid =
+            || ImportError::Format(format!("line {}: expected `<piece>\\t<score>`", i + 1));
+        let (piece, score) = line.split_once('\t').ok_or_else(invalid)?;
+        let score = score.parse::<f64>().map_err(|_| invalid())?;
+
+        if score == 0.0 && CONTROL_PIECES.contains(&piece) {
+            skipped.push(piece.to_string());
+            tokens.push(None);
+        } else {
+            tokens.push(Some(piece_to_bytes(piece)));
+        }
+    }
+
+    Ok((tokens, skipped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vocab() {
+        let (tokens, skipped) =
+            parse("<unk>\t0\n<s>\t0\n<0x0A>\t0\n\u{2581}the\t-3.5\n<br>\t-7\n").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                None,
+                None,
+                Some(b"\n".to_vec()),
+                Some(b" the".to_vec()),
+                Some(b"<br>".to_vec())
+            ]
+        );
+        assert_eq!(skipped, vec!["<unk>", "<s>"]);
+        assert!(parse("abc\n").is_err());
+    }
+}
@@ -0,0 +1,39 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use super::{ImportError, ParsedVocabulary};
+
+/// Parses a tiktoken rank file. Returns the tokens ordered by rank.
+pub fn parse(contents: &str) -> Result<ParsedVocabulary, ImportError> {
+    let mut ranked = Vec::new();
+
+    for (i, line) in contents.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || ImportError::Format(format!("line {}: expected `<base64> <rank>`", i + 1));
+
+        let (token, rank) = line.split_once(' ').ok_or_else(invalid)?;
+        let token = STANDARD.decode(token).map_err(|_| invalid())?;
+        let rank = rank.parse::<u64>().map_err(|_| invalid())?;
+        ranked.push((rank, token));
+    }
+    ranked.sort();
+
+    Ok((
+        ranked.into_iter().map(|(_, t)| Some(t)).collect(),
+        Vec::new(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranks() {
+        let (tokens, _) = parse("IHRoZQ== 1\nYQ== 0\n").unwrap();
+        assert_eq!(tokens, vec![Some(b"a".to_vec()), Some(b" the".to_vec())]);
+        assert!(parse("YQ==\n").is_err());
+    }
+}
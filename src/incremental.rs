@@ -0,0 +1,330 @@
+//! Incremental retokenization of a sample when a single token is added to or
+//! removed from the token set.
+//!
+//! The sample is split into chunks at sample and paragraph boundaries, which
+//! are tokenized independently. Adding or removing a token can only change
+//! the tokenization of the chunks containing its string, so only those are
+//! tokenized again, with both token sets, and the stats of the old token set
+//! are patched by the difference.
+//!
+//! The stats differ from those of `tokenize_file`, which tokenizes whole
+//! samples, only by the pairs of tokens around paragraph breaks, unless some
+//! token spans a paragraph break.
+
+use super::batch_tokenize::paragraph_starts;
+use super::input::sample::Sampler;
+use super::stats2::TokenStats;
+use super::tokenizer2::FragmentTokenizer;
+use super::tokenset::{Token, TokenSet};
+
+/// The chunks of a sample, with an inverted index of the pairs of bytes
+/// occurring in them.
+pub struct ChunkIndex {
+    data: Vec<u8>,
+    /// The start of every chunk in `data`, followed by `data.len()`.
+    bounds: Vec<usize>,
+    /// For every pair of bytes, the indices of the chunks containing it, in
+    /// increasing order.
+    bigram_chunks: Vec<Vec<u32>>,
+}
+
+impl ChunkIndex {
+    pub fn new<'a, S: Sampler<'a>>(sampler: &'a S) -> Self {
+        let mut data = Vec::new();
+        let mut bounds = Vec::new();
+
+        for sample in sampler.iter() {
+            let bytes = sample.as_bytes();
+            if bytes.is_empty() {
+                continue;
+            }
+            bounds.extend(paragraph_starts(bytes).into_iter().map(|p| data.len() + p));
+            data.extend_from_slice(bytes);
+        }
+        bounds.push(data.len());
+
+        let mut bigram_chunks = vec![Vec::new(); 1 << 16];
+        for chunk in 0..bounds.len() - 1 {
+            for pair in data[bounds[chunk]..bounds[chunk + 1]].windows(2) {
+                let chunks = &mut bigram_chunks[bigram(pair)];
+                if chunks.last() != Some(&(chunk as u32)) {
+                    chunks.push(chunk as u32);
+                }
+            }
+        }
+
+        ChunkIndex {
+            data,
+            bounds,
+            bigram_chunks,
+        }
+    }
+
+    pub fn nchunks(&self) -> usize {
+        self.bounds.len() - 1
+    }
+
+    pub fn chunk(&self, idx: usize) -> &[u8] {
+        &self.data[self.bounds[idx]..self.bounds[idx + 1]]
+    }
+
+    /// Returns the indices of the chunks containing `string`, which has to be
+    /// at least two bytes long.
+    pub fn chunks_containing(&self, string: &[u8]) -> Vec<usize> {
+        let candidates = string
+            .windows(2)
+            .map(|pair| &self.bigram_chunks[bigram(pair)])
+            .min_by_key(|chunks| chunks.len())
+            .unwrap();
+
+        candidates
+            .iter()
+            .map(|&chunk| chunk as usize)
+            .filter(|&chunk| self.chunk(chunk).windows(string.len()).any(|w| w == string))
+            .collect()
+    }
+
+    /// Tokenizes all chunks, collecting the stats with pair counts.
+    pub fn tokenize(&self, token_set: &TokenSet, initial_size: Option<u64>) -> TokenStats {
+        let tokenizer = FragmentTokenizer::new(token_set.clone());
+        let mut stats = TokenStats::new(token_set.clone(), initial_size);
+
+        if self.data.len() < 1 << 25 {
+            self.process_chunks(&tokenizer, 0..self.nchunks(), &mut stats);
+            return stats;
+        }
+
+        let nthreads = std::thread::available_parallelism().unwrap().get();
+        let part = self.nchunks().div_ceil(nthreads);
+
+        let results = std::thread::scope(|s| {
+            let handles = (0..self.nchunks())
+                .step_by(part)
+                .map(|start| {
+                    let tokenizer = &tokenizer;
+                    s.spawn(move || {
+                        let mut stats = TokenStats::new(token_set.clone(), None);
+                        let end = (start + part).min(self.nchunks());
+                        self.process_chunks(tokenizer, start..end, &mut stats);
+                        stats
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        for result in results.iter() {
+            stats.merge(result);
+        }
+        stats
+    }
+
+    fn process_chunks(
+        &self,
+        tokenizer: &FragmentTokenizer,
+        chunks: impl Iterator<Item = usize>,
+        stats: &mut TokenStats,
+    ) {
+        let mut buffer = Vec::new();
+        for chunk in chunks {
+            tokenizer.process_slice(self.chunk(chunk), stats, &mut buffer);
+        }
+    }
+
+    /// Derives the stats of `token_set` from the stats of `base`, if the two
+    /// token sets differ by a single `Str` token of at least two bytes. Both
+    /// token sets must be sorted and `base` has to be computed by this index,
+    /// with pair counts.
+    pub fn patch_stats(&self, base: &TokenStats, token_set: &TokenSet) -> Option<TokenStats> {
+        if base.token_set.tokens == token_set.tokens
+            && base.token_set.sequences == token_set.sequences
+        {
+            return Some(base.clone());
+        }
+
+        let (string, new_ids) = single_token_change(&base.token_set, token_set)?;
+        let chunks = self.chunks_containing(&string);
+
+        // Tokenizing the whole sample once is faster than tokenizing most of
+        // it twice.
+        let affected_bytes = chunks.iter().map(|&c| self.chunk(c).len()).sum::<usize>();
+        if 2 * affected_bytes > self.data.len() {
+            return None;
+        }
+
+        let mut old = TokenStats::new(base.token_set.clone(), None);
+        self.process_chunks(
+            &FragmentTokenizer::new(base.token_set.clone()),
+            chunks.iter().copied(),
+            &mut old,
+        );
+
+        let mut stats = TokenStats::new(token_set.clone(), base.initial_size);
+        self.process_chunks(
+            &FragmentTokenizer::new(token_set.clone()),
+            chunks.iter().copied(),
+            &mut stats,
+        );
+
+        stats.total_tokens += base.total_tokens - old.total_tokens;
+        stats.scanned_bytes += base.scanned_bytes - old.scanned_bytes;
+
+        for (old_id, &new_id) in new_ids.iter().enumerate() {
+            let delta = base.token_counts[old_id] - old.token_counts[old_id];
+            match new_id {
+                Some(new_id) => stats.token_counts[new_id] += delta,
+                None => assert_eq!(delta, 0),
+            }
+        }
+        for (seq_id, &count) in base.seq_counts.iter().enumerate() {
+            stats.seq_counts[seq_id] += count - old.seq_counts[seq_id];
+        }
+        for (&(first, second), &count) in base.pair_counts.iter() {
+            let delta = count - old.pair_counts.get(&(first, second)).unwrap_or(&0);
+            if delta > 0 {
+                let first = new_ids[first as usize].unwrap() as u32;
+                let second = new_ids[second as usize].unwrap() as u32;
+                *stats.pair_counts.entry((first, second)).or_insert(0) += delta;
+            }
+        }
+
+        Some(stats)
+    }
+}
+
+fn bigram(pair: &[u8]) -> usize {
+    (pair[0] as usize) << 8 | pair[1] as usize
+}
+
+/// If `token_set` has one `Str` token of at least two bytes more or less than
+/// `base`, and otherwise the same tokens and sequences, returns the string of
+/// that token and the IDs of the tokens of `base` in `token_set`. Both token
+/// sets must be sorted.
+fn single_token_change(
+    base: &TokenSet,
+    token_set: &TokenSet,
+) -> Option<(Vec<u8>, Vec<Option<usize>>)> {
+    let (old_tokens, new_tokens) = (&base.tokens, &token_set.tokens);
+    if old_tokens.len().abs_diff(new_tokens.len()) != 1
+        || base.sequences.len() != token_set.sequences.len()
+    {
+        return None;
+    }
+
+    let mut new_ids = vec![None; old_tokens.len()];
+    let mut changed = None;
+    let (mut i, mut j) = (0, 0);
+
+    while i < old_tokens.len() || j < new_tokens.len() {
+        if i < old_tokens.len() && j < new_tokens.len() && old_tokens[i] == new_tokens[j] {
+            new_ids[i] = Some(j);
+            i += 1;
+            j += 1;
+        } else if changed.is_some() {
+            return None;
+        } else if old_tokens.len() > new_tokens.len() {
+            changed = Some(&old_tokens[i]);
+            i += 1;
+        } else {
+            changed = Some(&new_tokens[j]);
+            j += 1;
+        }
+    }
+
+    let string = match changed {
+        Some(Token::Str(s)) if s.len() >= 2 => s.clone(),
+        _ => return None,
+    };
+
+    for (old_seq, new_seq) in base.sequences.iter().zip(token_set.sequences.iter()) {
+        if old_seq.string != new_seq.string
+            || old_seq.tokens.len() != new_seq.tokens.len()
+            || old_seq
+                .tokens
+                .iter()
+                .zip(new_seq.tokens.iter())
+                .any(|(&old_id, &new_id)| new_ids[old_id] != Some(new_id))
+        {
+            return None;
+        }
+    }
+
+    Some((string, new_ids))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::memory_sampler::MemorySampler;
+    use crate::processing::Processing;
+
+    const TEXT: &str = "the cat sat\n\non the mat\n\n\nthe end\n\nof the story";
+
+    #[test]
+    fn chunks() {
+        let sampler = MemorySampler::from_str(TEXT, 1 << 10);
+        let index = ChunkIndex::new(&sampler);
+
+        assert_eq!(index.nchunks(), 4);
+        assert_eq!(index.chunk(1), b"on the mat\n\n\n");
+        assert_eq!(index.chunks_containing(b"the"), vec![0, 1, 2, 3]);
+        assert_eq!(index.chunks_containing(b"at"), vec![0, 1]);
+        assert_eq!(index.chunks_containing(b"e e"), vec![2]);
+        assert!(index.chunks_containing(b"ta").is_empty());
+    }
+
+    #[test]
+    fn patch_matches_full() {
+        let text = TEXT.to_string() + "\n\n" + &"one two four\n\n".repeat(40) + "xyz";
+        let sampler = MemorySampler::from_str(&text, 100);
+        let index = ChunkIndex::new(&sampler);
+
+        let mut base_set = TokenSet::new_bits4(Processing::Raw, true);
+        for token in ["t", "h", "e", " ", "a", "at", "the"] {
+            base_set.add_token(token.as_bytes());
+        }
+        base_set.sort();
+        let base = index.tokenize(&base_set, Some(1000));
+
+        let changes = [
+            (Some("yz"), None),
+            (Some("he"), None),
+            (None, Some("at")),
+            (None, Some("the")),
+        ];
+        for (add, remove) in changes {
+            let mut token_set = base_set.clone();
+            if let Some(token) = add {
+                token_set.add_token(token.as_bytes());
+            }
+            if let Some(token) = remove {
+                let idx = token_set.find_token(token.as_bytes()).unwrap();
+                token_set.remove_token(idx);
+            }
+            token_set.sort();
+
+            let patched = index.patch_stats(&base, &token_set).unwrap();
+            let full = index.tokenize(&token_set, Some(1000));
+            assert_eq!(patched.total_tokens, full.total_tokens);
+            assert_eq!(patched.scanned_bytes, full.scanned_bytes);
+            assert_eq!(patched.token_counts, full.token_counts);
+            assert_eq!(patched.seq_counts, full.seq_counts);
+            assert_eq!(patched.pair_counts, full.pair_counts);
+        }
+
+        // "o " occurs in most chunks.
+        let mut token_set = base_set.clone();
+        token_set.add_token(b"o ");
+        token_set.sort();
+        assert!(index.patch_stats(&base, &token_set).is_none());
+
+        let mut token_set = base_set.clone();
+        token_set.add_token(b"ab");
+        token_set.add_token(b"ba");
+        token_set.sort();
+        assert!(index.patch_stats(&base, &token_set).is_none());
+    }
+}
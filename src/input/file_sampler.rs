@@ -1,12 +1,13 @@
 use rand::Rng;
 
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{self, Read, Seek, SeekFrom};
 use std::iter::Iterator;
 
+use crate::digest::Digest;
 use crate::input::sample::{Sample, Sampler};
 
-use super::util::find_paragraph_end;
+use super::util::{find_char_end, find_paragraph_end};
 
 pub struct FileSampler {
     filename: String,
@@ -16,13 +17,13 @@ pub struct FileSampler {
 }
 
 impl FileSampler {
-    pub fn new(filename: &str, sample_size: usize, max_samples: Option<usize>) -> Self {
-        FileSampler {
+    pub fn new(filename: &str, sample_size: usize, max_samples: Option<usize>) -> io::Result<Self> {
+        Ok(FileSampler {
             filename: filename.to_string(),
             sample_size,
             max_samples,
-            file_size: std::fs::metadata(filename).unwrap().len(),
-        }
+            file_size: std::fs::metadata(filename)?.len(),
+        })
     }
 }
 
@@ -58,6 +59,30 @@ impl<'a> Sampler<'a> for FileSampler {
             self.file_size
         }
     }
+
+    /// Identifies the file by its canonical path, size and modification time
+    /// instead of reading it.
+    fn digest(&'a self) -> Option<u128> {
+        if self.max_samples.is_some() {
+            // The samples are chosen at random.
+            return None;
+        }
+        let path = std::fs::canonicalize(&self.filename).ok()?;
+        let metadata = std::fs::metadata(&path).ok()?;
+        let modified = metadata
+            .modified()
+            .ok()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()?;
+
+        let mut digest = Digest::new();
+        digest.write_bytes(path.to_string_lossy().as_bytes());
+        digest.write_u64(metadata.len());
+        digest.write_u64(modified.as_secs());
+        digest.write_u64(modified.subsec_nanos() as u64);
+        digest.write_u64(self.sample_size as u64);
+        Some(digest.finish())
+    }
 }
 
 pub struct FileIterator<'a> {
@@ -72,8 +97,7 @@ impl<'a> Iterator for FileIterator<'a> {
     type Item = Sample<'a>;
 
     fn next(&mut self) -> Option<Sample<'a>> {
-        let mut buffer = Vec::new();
-        buffer.resize(self.sample_size, 0);
+        let mut buffer = vec![0; self.sample_size];
 
         if let Some(samples_left) = self.samples_left {
             if samples_left == 0 {
@@ -102,7 +126,11 @@ impl<'a> Iterator for FileIterator<'a> {
                 buffer.truncate(read_bytes);
                 Some(Sample::from_vec(buffer))
             } else {
-                let end = find_paragraph_end(&buffer, read_bytes);
+                let mut end = find_paragraph_end(&buffer, read_bytes);
+                if end == read_bytes {
+                    // No paragraph break, so at least don't split a character.
+                    end = find_char_end(&buffer);
+                }
                 if end < read_bytes {
                     buffer.truncate(end);
                     self.file
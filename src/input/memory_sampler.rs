@@ -1,36 +1,51 @@
-use std::io::{BufReader, BufRead};
 use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::sync::OnceLock;
 
-use crate::input::sample::{Sample, Sampler};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::batch_tokenize::paragraph_starts;
+use crate::input::sample::{digest_samples, Sample, Sampler};
 
 use super::util::find_paragraph_end;
 
 pub struct MemorySampler {
     data: Vec<u8>,
     chunk_size: usize,
+    /// Computed on the first use, as every cache would hash the data again.
+    digest: OnceLock<u128>,
 }
 
 impl MemorySampler {
-    pub fn from_file(filename: &str, chunk_size: usize) -> Self {
-        let data = std::fs::read(filename).unwrap();
-        MemorySampler { data, chunk_size }
+    fn new(data: Vec<u8>, chunk_size: usize) -> Self {
+        MemorySampler {
+            data,
+            chunk_size,
+            digest: OnceLock::new(),
+        }
+    }
+
+    pub fn from_file(filename: &str, chunk_size: usize) -> io::Result<Self> {
+        let data = std::fs::read(filename)?;
+        Ok(MemorySampler::new(data, chunk_size))
     }
 
     /// Create a sampler by concatenating random full paragraphs from the file
     /// to reach approximately `size` bytes. The fragments read from the file will
     /// be distributed uniformly across the file.
-    pub fn sample_from_file(filename: &str, size: usize, chunk_size: usize) -> Self {
-        let file_size = std::fs::metadata(filename).unwrap().len() as usize;
+    pub fn sample_from_file(filename: &str, size: usize, chunk_size: usize) -> io::Result<Self> {
+        let file_size = std::fs::metadata(filename)?.len() as usize;
         let target_share = size as f64 / file_size as f64;
         let mut data = Vec::new();
-        let file = File::open(filename).unwrap();
+        let file = File::open(filename)?;
         let mut reader = BufReader::new(file);
-        
+
         let mut paragraph = Vec::new();
         let mut buffer = Vec::new();
         let mut read_bytes = 0;
 
-        while reader.read_until(10, &mut buffer).unwrap() > 0 {
+        while reader.read_until(10, &mut buffer)? > 0 {
             if buffer[0] != 10 && paragraph.ends_with(&[10, 10]) {
                 // We have a full paragraph
 
@@ -50,14 +65,44 @@ impl MemorySampler {
             data.extend_from_slice(&paragraph);
         }
 
-        MemorySampler { data, chunk_size }
+        Ok(MemorySampler::new(data, chunk_size))
     }
 
     pub fn from_str(data: &str, chunk_size: usize) -> Self {
-        MemorySampler {
-            data: data.as_bytes().to_vec(),
-            chunk_size: chunk_size,
+        MemorySampler::new(data.as_bytes().to_vec(), chunk_size)
+    }
+
+    /// Splits the data by paragraphs into a training and a held-out sampler.
+    /// Every paragraph is held out with probability `share`, but at least one
+    /// if there are several and `share` isn't 0. The same seed gives the same
+    /// split.
+    pub fn split(&self, share: f64, seed: u64) -> (MemorySampler, MemorySampler) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut starts = paragraph_starts(&self.data);
+        starts.push(self.data.len());
+
+        let held_out = starts
+            .windows(2)
+            .map(|_| rng.gen_bool(share))
+            .collect::<Vec<_>>();
+        let any_held_out = held_out.iter().any(|&h| h);
+
+        let mut train = Vec::new();
+        let mut validation = Vec::new();
+        for (i, bounds) in starts.windows(2).enumerate() {
+            let paragraph = &self.data[bounds[0]..bounds[1]];
+            let last = i + 1 == held_out.len();
+            if held_out[i] || (last && !any_held_out && i > 0 && share > 0.0) {
+                validation.extend_from_slice(paragraph);
+            } else {
+                train.extend_from_slice(paragraph);
+            }
         }
+
+        (
+            MemorySampler::new(train, self.chunk_size),
+            MemorySampler::new(validation, self.chunk_size),
+        )
     }
 }
 
@@ -74,6 +119,10 @@ impl<'a> Sampler<'a> for MemorySampler {
     fn total_size(&'a self) -> u64 {
         self.data.len() as u64
     }
+
+    fn digest(&'a self) -> Option<u128> {
+        Some(*self.digest.get_or_init(|| digest_samples(self.iter())))
+    }
 }
 
 pub struct MemoryIterator<'a> {
@@ -87,8 +136,7 @@ impl<'a> Iterator for MemoryIterator<'a> {
     fn next(&mut self) -> Option<Sample<'a>> {
         if self.position < self.sampler.data.len() {
             let start = self.position;
-            self.position =
-                std::cmp::min(start + self.sampler.chunk_size, self.sampler.data.len());
+            self.position = std::cmp::min(start + self.sampler.chunk_size, self.sampler.data.len());
             let paragraph_end = find_paragraph_end(&self.sampler.data, self.position);
             if self.position < self.sampler.data.len() && paragraph_end > start {
                 self.position = paragraph_end;
@@ -99,3 +147,39 @@ impl<'a> Iterator for MemoryIterator<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split() {
+        let text = (0..100)
+            .map(|i| format!("paragraph {}\n\n", i))
+            .collect::<String>();
+        let sampler = MemorySampler::from_str(&text, 1 << 10);
+
+        let (train, validation) = sampler.split(0.2, 1);
+        let train = String::from_utf8(train.data).unwrap();
+        let validation = String::from_utf8(validation.data).unwrap();
+        assert_eq!(train.len() + validation.len(), text.len());
+        let held_out = validation.matches("paragraph").count();
+        assert!((5..40).contains(&held_out));
+        for paragraph in validation.split_terminator("\n\n") {
+            assert!(!train.contains(&format!("{}\n", paragraph)));
+        }
+
+        let (_, same) = sampler.split(0.2, 1);
+        assert_eq!(same.data, validation.as_bytes());
+        let (_, other) = sampler.split(0.2, 2);
+        assert_ne!(other.data, validation.as_bytes());
+
+        let (train, validation) = sampler.split(1e-9, 1);
+        assert_eq!(validation.data, b"paragraph 99\n\n");
+        assert_eq!(train.data.len() + validation.data.len(), text.len());
+
+        let (train, validation) = sampler.split(0.0, 1);
+        assert!(validation.data.is_empty());
+        assert_eq!(train.data, text.as_bytes());
+    }
+}
@@ -1,5 +1,7 @@
-pub mod sample;
 pub mod file_sampler;
 pub mod memory_sampler;
 pub mod preloaded_sampler;
-mod util;
\ No newline at end of file
+pub mod sample;
+mod util;
+
+pub use util::check_utf8_file;
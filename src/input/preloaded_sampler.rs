@@ -23,11 +23,6 @@ impl PreloadedSampler {
             (sample_size, max_samples)
         };
 
-        println!(
-            "Preparing SelectionSampler with {} chunks x {} bytes",
-            nsamples, sample_size
-        );
-
         let step = data_len / nsamples;
 
         let mut file = File::open(filename).unwrap();
@@ -36,8 +31,7 @@ impl PreloadedSampler {
 
         for i in 0..nsamples as u64 {
             file.seek(SeekFrom::Start(i * step as u64)).unwrap();
-            let mut chunk = Vec::new();
-            chunk.resize(sample_size, 0);
+            let mut chunk = vec![0; sample_size];
             let read_bytes = file.read(&mut chunk).unwrap();
             chunk.truncate(read_bytes);
 
@@ -50,7 +44,7 @@ impl PreloadedSampler {
             } else {
                 chunks.push(valid_chunk.to_vec());
             };
-        }            
+        }
 
         let _total_size = chunks.iter().map(|c| c.len() as u64).sum();
         PreloadedSampler {
@@ -1,3 +1,5 @@
+use crate::digest::Digest;
+
 pub enum Sample<'a> {
     Data(String),
     Ref(&'a str),
@@ -8,7 +10,7 @@ impl<'a> Sample<'a> {
     /// The bytes should be valid UTF-8.
     pub fn from_vec(data: Vec<u8>) -> Self {
         Sample::Data(String::from_utf8(data).unwrap())
-    }   
+    }
 
     pub fn from_bytes(data: &'a [u8]) -> Self {
         match String::from_utf8_lossy(data) {
@@ -38,5 +40,19 @@ pub trait Sampler<'a> {
     fn iter(&'a self) -> Self::Iter;
 
     fn total_size(&'a self) -> u64;
+
+    /// A stable digest of the samples, identifying the data in caches. `None`
+    /// if the samples differ between iterations.
+    fn digest(&'a self) -> Option<u128> {
+        Some(digest_samples(self.iter()))
+    }
 }
 
+/// The digest of a sequence of samples, including their boundaries.
+pub fn digest_samples<'a>(samples: impl Iterator<Item = Sample<'a>>) -> u128 {
+    let mut digest = Digest::new();
+    for sample in samples {
+        digest.write_bytes(sample.as_bytes());
+    }
+    digest.finish()
+}
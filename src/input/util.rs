@@ -1,8 +1,11 @@
+use std::fs::File;
+use std::io::{self, Read};
+
 fn is_char_start(b: u8) -> bool {
-    b < 128 || b >= 192
+    !(128..192).contains(&b)
 }
 
-pub fn extract_valid_utf8_slice<'a>(data: &'a [u8]) -> &'a [u8] {
+pub fn extract_valid_utf8_slice(data: &[u8]) -> &[u8] {
     let mut start = 0;
     while start < data.len() && !is_char_start(data[start]) {
         start += 1;
@@ -23,5 +26,84 @@ pub fn find_paragraph_end(data: &[u8], end: usize) -> usize {
         pos -= 1;
     }
 
-    if pos < 2 { end } else { pos }
+    if pos < 2 {
+        end
+    } else {
+        pos
+    }
+}
+
+/// Returns the length of the longest prefix of `data` which doesn't end in the
+/// middle of a UTF-8 character.
+pub fn find_char_end(data: &[u8]) -> usize {
+    match std::str::from_utf8(data) {
+        Err(e) if e.error_len().is_none() && e.valid_up_to() > 0 => e.valid_up_to(),
+        _ => data.len(),
+    }
+}
+
+/// Checks that the file is valid UTF-8, reading it in chunks.
+pub fn check_utf8_file(filename: &str) -> io::Result<()> {
+    let mut file = File::open(filename)?;
+    let mut buffer = vec![0; 1 << 20];
+    let mut pending = 0;
+    let mut offset = 0;
+
+    let invalid = |offset: usize| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not valid UTF-8 (at byte {})", filename, offset),
+        )
+    };
+
+    loop {
+        let read_bytes = file.read(&mut buffer[pending..])?;
+        if read_bytes == 0 {
+            return if pending == 0 {
+                Ok(())
+            } else {
+                Err(invalid(offset))
+            };
+        }
+        let len = pending + read_bytes;
+        match std::str::from_utf8(&buffer[..len]) {
+            Ok(_) => {
+                offset += len;
+                pending = 0;
+            }
+            Err(e) if e.error_len().is_none() => {
+                // The chunk ends in the middle of a character.
+                let valid = e.valid_up_to();
+                buffer.copy_within(valid..len, 0);
+                offset += valid;
+                pending = len - valid;
+            }
+            Err(e) => return Err(invalid(offset + e.valid_up_to())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    #[test]
+    fn find_char_end() {
+        assert_eq!(super::find_char_end("aé".as_bytes()), 3);
+        assert_eq!(super::find_char_end(&"aé".as_bytes()[..2]), 1);
+        assert_eq!(super::find_char_end(&[b'a', 0xff]), 2);
+    }
+
+    #[test]
+    fn check_utf8_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all("Straße\n\n".repeat(100_000).as_bytes())
+            .unwrap();
+        let filename = file.path().to_str().unwrap().to_string();
+        assert!(super::check_utf8_file(&filename).is_ok());
+
+        file.write_all(&[b'a', 0xc3]).unwrap();
+        let err = super::check_utf8_file(&filename).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
 }
@@ -0,0 +1,58 @@
+//! Optimizing sets of tokens for language models.
+//!
+//! A token set is loaded with `read_token_set` (or built with the `TokenSet`
+//! constructors), the text is prepared with `process`, encoded into token IDs
+//! with `FragmentTokenizer` and decoded back with `Decoder`. `Optimizer`
+//! searches for a token set minimizing the number of tokens needed to encode
+//! the data. Its progress is printed only with `OptimizerOptions::verbose`.
+//!
+//! ```
+//! use texmo::{Decoder, FragmentTokenizer, Processing, TokenSet};
+//!
+//! let mut token_set = TokenSet::new_bytes(Processing::Raw);
+//! token_set.add_token("ab".as_bytes());
+//!
+//! let tokenizer = FragmentTokenizer::new(token_set.clone());
+//! let token_ids = tokenizer.encode("abc".as_bytes(), &mut Vec::new());
+//! assert_eq!(token_ids.len(), 2);
+//!
+//! let decoder = Decoder::new(&token_set);
+//! assert_eq!(decoder.decode(&token_ids).unwrap(), "abc".as_bytes());
+//! ```
+
+mod batch_tokenize;
+mod checkpoint;
+mod decoder;
+mod digest;
+pub mod export;
+pub mod import;
+mod incremental;
+mod input;
+mod migrate;
+mod optimize;
+mod optimize_bytes;
+mod processing;
+mod stats2;
+mod tokenizer2;
+mod tokenset;
+mod validate;
+
+pub use batch_tokenize::{encode_file, tokenize_file, EncodedSample, TokenizerCache};
+pub use checkpoint::{load_checkpoint, truncate_log, CheckpointError, OptimizerState, StopReason};
+pub use decoder::{DecodeError, Decoder};
+pub use input::check_utf8_file;
+pub use input::file_sampler::FileSampler;
+pub use input::memory_sampler::MemorySampler;
+pub use input::preloaded_sampler::PreloadedSampler;
+pub use input::sample::{Sample, Sampler};
+pub use optimize::{request_stop, split_validation, OptimizeError, Optimizer, OptimizerOptions};
+pub use processing::{
+    maybe_process_file, process, process_file, process_file_with_escaping, process_with_escaping,
+    unprocess, unprocess_file, unprocess_with_escaping, Processing, ESCAPING_VERSION,
+};
+pub use stats2::TokenStats;
+pub use tokenizer2::FragmentTokenizer;
+pub use tokenset::{
+    read_token_set, ProblemKind, Token, TokenSet, TokenSetError, TokenSetProblem, TokenType,
+};
+pub use validate::{check_token_set_file, check_token_set_json};
@@ -1,48 +1,20 @@
-#![allow(dead_code)]
-
 use clap::{Parser, Subcommand};
-use serde_json::Value;
+use serde_json::json;
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::path::Path;
-use tempfile::NamedTempFile;
-
-mod batch_tokenize;
-mod input;
-mod optimize;
-mod optimize_bytes;
-mod processing;
-mod stats2;
-mod tokenizer2;
-mod tokenset;
-
-use self::input::file_sampler::FileSampler;
-use self::input::memory_sampler::MemorySampler;
-use self::processing::{process_file, Processing};
-use self::stats2::TokenStats;
-use self::input::sample::Sampler;
-use self::tokenset::{TokenSet, TokenType};
-
-fn maybe_process_file(
-    filename_raw: &str,
-    filename_processed: Option<&str>,
-    processing: Processing,
-) -> (String, Option<NamedTempFile>) {
-    match (filename_processed, processing) {
-        (_, Processing::Raw) => (filename_raw.to_string(), None),
-        (Some(f), Processing::CapsWords) => (f.to_string(), None),
-        (None, Processing::CapsWords) => {
-            println!("Pre-processing the data file... ");
-            let mut temp_processed = NamedTempFile::new().unwrap();
-            let mut input = File::open(filename_raw).unwrap();
-            process_file(&mut input, &mut temp_processed).unwrap();
-            println!("done");
-            let filename = temp_processed.path().to_str().unwrap().to_string();
-            (filename, Some(temp_processed))
-        }
-    }
-}
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use texmo::export::{huggingface, tiktoken};
+use texmo::import::{self, VocabFormat};
+use texmo::{
+    check_token_set_file, check_utf8_file, encode_file, load_checkpoint, maybe_process_file,
+    process_file, read_token_set, request_stop, split_validation, tokenize_file, truncate_log,
+    unprocess_with_escaping, Decoder, FileSampler, MemorySampler, Optimizer, OptimizerOptions,
+    OptimizerState, Processing, Sampler, TokenSet, TokenStats, TokenType, TokenizerCache,
+    ESCAPING_VERSION,
+};
 
 fn process(filename: &str, output: &str) {
     let mut input = File::open(filename).unwrap();
@@ -102,14 +74,28 @@ fn count_chars(filename: &str) {
     println!("Max char: {:?}", std::char::from_u32(max_c).unwrap());
 }
 
-fn read_token_set(filename: &str) -> TokenSet {
-    let path = Path::new(filename);
-    let input_tokens_file = File::open(path).expect("Input tokens file not found");
-    let reader = BufReader::new(input_tokens_file);
+/// Returns the value, or prints the error and exits.
+fn or_exit<T, E: std::fmt::Display>(result: Result<T, E>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1)
+    })
+}
+
+/// Parses `--validation-share`, which has to be greater than 0 and less than 1.
+fn parse_share(s: &str) -> Result<f64, String> {
+    let share = s.parse::<f64>().map_err(|e| e.to_string())?;
+    if !(share > 0.0 && share < 1.0) {
+        return Err("the share has to be greater than 0 and less than 1".to_string());
+    }
+    Ok(share)
+}
 
-    // Deserialize the JSON data into a serde_json::Value
-    let tokenset_json: Value = serde_json::from_reader(reader).unwrap();
-    TokenSet::from_json(tokenset_json)
+fn load_token_set(filename: &str) -> TokenSet {
+    read_token_set(filename).unwrap_or_else(|e| {
+        eprintln!("Can't load the token set from {}:\n{}", filename, e);
+        std::process::exit(1)
+    })
 }
 
 fn load_save_tokens(
@@ -119,21 +105,25 @@ fn load_save_tokens(
     tokens_dir: &str,
 ) {
     let tokens_dir_path = Path::new(tokens_dir);
-    let token_set = read_token_set(input_tokens_path);
+    let token_set = load_token_set(input_tokens_path);
 
-    let (filename, _temp) =
-        maybe_process_file(filename_raw, filename_processed, token_set.processing);
+    let (filename, _temp) = or_exit(maybe_process_file(
+        filename_raw,
+        filename_processed,
+        token_set.processing,
+        token_set.escaping,
+    ));
     let initial_size = std::fs::metadata(filename_raw).unwrap().len();
 
     println!("Opening {}", &filename);
-    let sampler = FileSampler::new(&filename, 1 << 24, None);
+    let sampler = or_exit(FileSampler::new(&filename, 1 << 24, None));
 
     println!(
         "Tokenizing {} using token set {}.",
         &filename,
         token_set.name()
     );
-    let stats = batch_tokenize::tokenize_file(&token_set, &sampler, Some(initial_size));
+    let stats = tokenize_file(&token_set, &sampler, Some(initial_size));
 
     let output_path = tokens_dir_path.join(format!("{}.json", token_set.name()));
     println!("Writing the token set to {}.", output_path.display());
@@ -141,63 +131,386 @@ fn load_save_tokens(
     std::fs::write(&output_path, serialized).unwrap();
 }
 
-fn save_tokens(stats: &TokenStats, tokens_dir: &Path) {
-    let output_path = tokens_dir.join(format!("{}.json", stats.token_set.name()));
-    println!("Writing the token set to {}.", output_path.display());
-    let serialized = serde_json::to_string(&stats.to_json()).unwrap();
-    std::fs::write(&output_path, serialized).unwrap();
+/// Tokenizes the data file and writes the token IDs to `output` as a flat
+/// array of little-endian u16 or u32 integers, depending on the number of
+/// tokens. The offsets of the paragraphs in the token array are written to
+/// `<output>.json`.
+fn tokenize(
+    filename_raw: &str,
+    filename_processed: Option<&str>,
+    input_tokens_path: &str,
+    output: &str,
+) {
+    let token_set = load_token_set(input_tokens_path);
+
+    let (filename, _temp) = or_exit(maybe_process_file(
+        filename_raw,
+        filename_processed,
+        token_set.processing,
+        token_set.escaping,
+    ));
+    if let Err(e) = check_utf8_file(&filename) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+    let sampler = or_exit(FileSampler::new(&filename, 1 << 24, None));
+
+    let use_u16 = token_set.ntokens() <= 1 << 16;
+    let mut writer = BufWriter::new(File::create(output).unwrap());
+    let mut offsets = Vec::new();
+    let mut total_tokens = 0;
+
+    println!(
+        "Tokenizing {} using token set {}.",
+        &filename,
+        token_set.name()
+    );
+    encode_file(&token_set, &sampler, |encoded| {
+        for &token_id in encoded.token_ids.iter() {
+            if use_u16 {
+                writer.write_all(&(token_id as u16).to_le_bytes()).unwrap();
+            } else {
+                writer.write_all(&token_id.to_le_bytes()).unwrap();
+            }
+        }
+        for &length in encoded.paragraph_lengths.iter() {
+            offsets.push(total_tokens);
+            total_tokens += length;
+        }
+    });
+    writer.flush().unwrap();
+
+    let index = json!({
+        "token_set": token_set.name(),
+        "dtype": if use_u16 { "u16" } else { "u32" },
+        "ntokens": total_tokens,
+        "documents": offsets,
+    });
+    let index_path = format!("{}.json", output);
+    println!(
+        "Wrote {} tokens to {}, index to {}.",
+        total_tokens, output, &index_path
+    );
+    std::fs::write(&index_path, serde_json::to_string(&index).unwrap()).unwrap();
 }
 
-fn optimize_with_increasing_data(
-    optimizer: &optimize::Optimizer,
-    filename: &str,
-    min_data_size: usize,
-    input_token_set: Option<TokenSet>,
-) -> TokenStats {
-    let full_sampler = FileSampler::new(filename, 1 << 24, None);
-    let full_size = std::fs::metadata(filename).unwrap().len() as usize;
-    let mut tokenset = input_token_set;
-    let mut size = min_data_size;
-    let mut full_stats = None;
-
-    while size < full_size {
-        let sampler = MemorySampler::sample_from_file(filename, size, 1 << 20);
-        println!("Optimizing with {} bytes of data.", sampler.total_size());
-
-        if let Some(tokenset) = tokenset.as_ref() {
-            let stats = optimizer.get_stats(&sampler, tokenset);
-            println!("bytes / token (bigger data): {}", stats.bytes_per_token());
+/// Reads token IDs from a binary file written by `tokenize`, using the
+/// integer width recorded in its `<filename>.json` index.
+fn read_token_ids(filename: &str) -> Result<Vec<u32>, String> {
+    let index_path = format!("{}.json", filename);
+    let index: serde_json::Value = std::fs::read_to_string(&index_path)
+        .map_err(|e| e.to_string())
+        .and_then(|s| serde_json::from_str(&s).map_err(|e| e.to_string()))
+        .map_err(|e| format!("Can't read the index {}: {}", index_path, e))?;
+    let width = match index["dtype"].as_str() {
+        Some("u16") => 2,
+        Some("u32") => 4,
+        _ => return Err(format!("{} has no valid \"dtype\"", index_path)),
+    };
+
+    let bytes = std::fs::read(filename).map_err(|e| format!("Can't read {}: {}", filename, e))?;
+    if bytes.len() % width != 0 {
+        return Err(format!(
+            "The size of {} ({} bytes) is not a multiple of {}",
+            filename,
+            bytes.len(),
+            width
+        ));
+    }
+    if let Some(ntokens) = index["ntokens"].as_u64() {
+        if ntokens as usize * width != bytes.len() {
+            return Err(format!(
+                "{} has {} tokens, but the index says {}",
+                filename,
+                bytes.len() / width,
+                ntokens
+            ));
         }
+    }
 
-        let stats = optimizer.optimize(&sampler, tokenset);
-        println!("bytes / token (optimized): {}", stats.bytes_per_token());
+    Ok(if width == 2 {
+        bytes
+            .chunks_exact(2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]) as u32)
+            .collect()
+    } else {
+        bytes
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect()
+    })
+}
 
-        let new_full_stats = optimizer.get_stats(&full_sampler, &stats.token_set);
-        println!(
-            "bytes / token (full data): {}",
-            new_full_stats.bytes_per_token()
-        );
+/// Reads token IDs from a JSON list.
+fn read_token_ids_json(filename: &str) -> Result<Vec<u32>, String> {
+    let contents =
+        std::fs::read_to_string(filename).map_err(|e| format!("Can't read {}: {}", filename, e))?;
+    serde_json::from_str(&contents)
+        .map_err(|e| format!("{} is not a JSON list of token IDs: {}", filename, e))
+}
 
-        if size > 1 << 24
-            && (stats.bytes_per_token() - new_full_stats.bytes_per_token()).abs()
-                / new_full_stats.bytes_per_token()
-                < 0.005
-        {
-            println!("The difference is less than 0.5%. Stopping.");
-            full_stats = Some(new_full_stats);
+/// Decodes the token IDs and restores the original text by undoing the
+/// processing of the token set.
+fn detokenize(input: &str, json: bool, input_tokens_path: &str, output: &str) {
+    let token_set = load_token_set(input_tokens_path);
+
+    let result = if json {
+        read_token_ids_json(input)
+    } else {
+        read_token_ids(input)
+    }
+    .and_then(|token_ids| {
+        Decoder::new(&token_set)
+            .decode(&token_ids)
+            .map_err(|e| format!("Can't decode {}: {}", input, e))
+    })
+    .and_then(|decoded| match token_set.processing {
+        Processing::Raw => Ok(decoded),
+        Processing::CapsWords => String::from_utf8(decoded)
+            .map(|processed| unprocess_with_escaping(&processed, token_set.escaping).into_bytes())
+            .map_err(|e| format!("The decoded text is not valid UTF-8: {}", e)),
+    });
+
+    let text = result.unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1)
+    });
+
+    println!("Writing {} bytes to {}.", text.len(), output);
+    std::fs::write(output, text).unwrap();
+}
+
+/// Writes the token set as a HuggingFace `tokenizer.json` and prints what
+/// can't be exported exactly.
+fn export_hf(input_tokens_path: &str, output: &str) {
+    let token_set = load_token_set(input_tokens_path);
+    let (tokenizer, issues) = huggingface::to_tokenizer_json(&token_set);
+
+    for issue in issues.iter() {
+        println!("{}", issue);
+    }
+    println!("Writing the tokenizer to {}.", output);
+    std::fs::write(output, serde_json::to_string_pretty(&tokenizer).unwrap()).unwrap();
+}
+
+/// Writes the token set as a tiktoken rank file, with the ranks ordered by
+/// the token counts on the data. With `check`, also compares the rank-merge
+/// encoding of the data with the optimal one.
+fn export_tiktoken(
+    filename_raw: &str,
+    filename_processed: Option<&str>,
+    input_tokens_path: &str,
+    output: &str,
+    check: bool,
+) {
+    let token_set = load_token_set(input_tokens_path);
+
+    let (filename, _temp) = or_exit(maybe_process_file(
+        filename_raw,
+        filename_processed,
+        token_set.processing,
+        token_set.escaping,
+    ));
+    let sampler = or_exit(FileSampler::new(&filename, 1 << 24, None));
+
+    println!(
+        "Counting tokens in {} using token set {}.",
+        &filename,
+        token_set.name()
+    );
+    let stats = tokenize_file(&token_set, &sampler, None);
+    let ranked = tiktoken::rank_tokens(&stats);
+
+    for issue in tiktoken::export_issues(&token_set, &ranked) {
+        println!("{}", issue);
+    }
+    println!("Writing {} ranks to {}.", ranked.len(), output);
+    let mut writer = BufWriter::new(File::create(output).unwrap());
+    tiktoken::write_ranks(&token_set, &ranked, &mut writer).unwrap();
+    writer.flush().unwrap();
+
+    if check {
+        println!("Comparing rank merging with the optimal tokenization.");
+        println!("{}", tiktoken::check_ranks(&token_set, &ranked, &sampler));
+    }
+}
+
+/// Imports the vocabulary of another tokenizer as a token set. With data,
+/// also tokenizes it and saves the stats with the token set.
+fn import_vocabulary(format: VocabFormat, input: &str, output: &str, data: Option<&str>) {
+    let (token_set, report) = import::read_vocabulary(format, input).unwrap_or_else(|e| {
+        eprintln!("Can't import the vocabulary from {}:\n{}", input, e);
+        std::process::exit(1)
+    });
+    println!("{}", report);
+
+    let value = match data {
+        Some(filename) => {
+            let initial_size = std::fs::metadata(filename).unwrap().len();
+            let sampler = or_exit(FileSampler::new(filename, 1 << 24, None));
+            println!("Tokenizing {} using the imported vocabulary.", filename);
+            let stats = tokenize_file(&token_set, &sampler, Some(initial_size));
+            println!(
+                "{} tokens, {:.4} bytes per token. These numbers come from the optimal \
+                 segmentation with the vocabulary, not from the merge-based tokenizer \
+                 it was imported from.",
+                stats.total_tokens,
+                stats.bytes_per_token()
+            );
+            stats.to_json()
+        }
+        None => token_set.to_json(),
+    };
+
+    println!("Writing the token set to {}.", output);
+    std::fs::write(output, serde_json::to_string(&value).unwrap()).unwrap();
+}
+
+/// Counts whitespace-separated words in the file.
+fn count_words(filename: &str) -> u64 {
+    let mut reader = BufReader::new(File::open(filename).unwrap());
+    let mut words = 0;
+    let mut in_word = false;
+
+    loop {
+        let buffer = reader.fill_buf().unwrap();
+        if buffer.is_empty() {
             break;
         }
+        for &b in buffer {
+            let is_space = b.is_ascii_whitespace();
+            if !in_word && !is_space {
+                words += 1;
+            }
+            in_word = !is_space;
+        }
+        let len = buffer.len();
+        reader.consume(len);
+    }
+
+    words
+}
+
+/// Tokenizes each data file with each token set and prints a table of the
+/// results. Each data file is processed at most once per processing type.
+fn compare(token_set_paths: &[String], data_paths: &[String], cache_dir: Option<&str>) {
+    let token_sets = token_set_paths
+        .iter()
+        .map(|path| load_token_set(path))
+        .collect::<Vec<_>>();
+
+    let mut rows = Vec::new();
+
+    for data_path in data_paths {
+        let initial_size = std::fs::metadata(data_path).unwrap().len();
+        let words = count_words(data_path);
+        let mut processed = Vec::new();
+        for token_set in token_sets.iter() {
+            let key = (token_set.processing, token_set.escaping);
+            if !processed.iter().any(|(k, _)| *k == key) {
+                let file = or_exit(maybe_process_file(data_path, None, key.0, key.1));
+                processed.push((key, file));
+            }
+        }
 
-        tokenset = Some(stats.token_set);
+        // One cache per processed file, so that the data is hashed only once.
+        let samplers = processed
+            .iter()
+            .map(|(k, (filename, _))| (*k, or_exit(FileSampler::new(filename, 1 << 24, None))))
+            .collect::<Vec<_>>();
+        let mut caches = samplers
+            .iter()
+            .map(|(k, sampler)| {
+                let cache = TokenizerCache::new(sampler, Some(initial_size));
+                match cache_dir {
+                    Some(dir) => (*k, cache.with_cache_dir(Path::new(dir))),
+                    None => (*k, cache),
+                }
+            })
+            .collect::<Vec<_>>();
 
-        size *= 2;
+        for (token_set_path, token_set) in token_set_paths.iter().zip(token_sets.iter()) {
+            let (_, cache) = caches
+                .iter_mut()
+                .find(|(k, _)| *k == (token_set.processing, token_set.escaping))
+                .unwrap();
+            let stats = cache.get_stats(token_set);
+
+            rows.push(format!(
+                "{:<40} {:<20} {:>8} {:>12.4} {:>12.4} {:>9.2}% {:>7.2}%",
+                file_name(token_set_path),
+                file_name(data_path),
+                token_set.ntokens(),
+                initial_size as f64 / stats.total_tokens as f64,
+                stats.total_tokens as f64 / words as f64,
+                100.0 * stats.ext_sequence_bytes() as f64 / stats.scanned_bytes as f64,
+                100.0 * stats.unused_tokens() as f64 / token_set.ntokens() as f64,
+            ));
+        }
     }
 
-    full_stats.unwrap()
+    println!(
+        "{:<40} {:<20} {:>8} {:>12} {:>12} {:>10} {:>8}",
+        "token set", "data", "ntokens", "bytes/token", "tokens/word", "ext bytes", "unused"
+    );
+    for row in rows {
+        println!("{}", row);
+    }
 }
 
+fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string())
+}
+
+/// Checks the token set files and prints the problems found. Exits with a
+/// non-zero status if there are any.
+fn validate(files: &[String]) {
+    let mut failed = 0;
+
+    for filename in files {
+        let problems = check_token_set_file(filename);
+        if problems.is_empty() {
+            println!("{}: OK", filename);
+        } else {
+            failed += 1;
+            println!("{}: {} problems", filename, problems.len());
+            for problem in problems.iter() {
+                println!("  {}", problem);
+            }
+        }
+    }
+
+    if failed > 0 {
+        println!("{} of {} token sets have problems.", failed, files.len());
+        std::process::exit(1);
+    }
+}
+
+/// Optimizes a token set, or a ladder of them if there are `sizes`.
+fn run_optimizer<'a>(
+    optimizer: &Optimizer,
+    sizes: &[usize],
+    nested: bool,
+    sampler: &'a (impl Sampler<'a> + Sync),
+    validation: Option<&MemorySampler>,
+    token_set: Option<TokenSet>,
+    state: &mut OptimizerState,
+) -> TokenStats {
+    if sizes.is_empty() {
+        optimizer.optimize_with_state(sampler, validation, token_set, state)
+    } else {
+        or_exit(optimizer.optimize_ladder(sizes, nested, sampler, validation, token_set, state))
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn optimize(
     ntokens: usize,
+    sizes: &[usize],
+    nested: bool,
     filename_raw: &str,
     filename_processed: Option<&str>,
     tokens_dir: &str,
@@ -205,17 +518,77 @@ fn optimize(
     token_type: TokenType,
     input_tokens: Option<&str>,
     min_data_size: Option<usize>,
+    resume: bool,
+    seed: u64,
+    options: OptimizerOptions,
 ) {
     let tokens_dir_path = Path::new(tokens_dir);
 
-    let (filename, _temp) = maybe_process_file(filename_raw, filename_processed, processing);
+    let (filename, _temp) = or_exit(maybe_process_file(
+        filename_raw,
+        filename_processed,
+        processing,
+        ESCAPING_VERSION,
+    ));
     let initial_size = std::fs::metadata(filename_raw).unwrap().len();
 
-    let input_token_set = if let Some(filename) = input_tokens {
-        println!("Reading the input token set from {}.", filename);
-        Some(read_token_set(filename))
+    let validation_share = options.validation_share;
+    let trajectory_log = options.trajectory_log.clone();
+    let optimizer = Optimizer::new(
+        ntokens,
+        processing,
+        token_type,
+        Some(initial_size),
+        tokens_dir_path,
+        options,
+    );
+
+    if !sizes.windows(2).all(|pair| pair[0] < pair[1]) {
+        eprintln!("The sizes have to be increasing.");
+        std::process::exit(1)
+    }
+    if nested && token_type != TokenType::Bytes {
+        eprintln!("Nested token sets need --type bytes.");
+        std::process::exit(1)
+    }
+
+    let (input_token_set, mut state) = if resume {
+        let dir = optimizer.checkpoint_dir();
+        println!("Resuming from the checkpoint in {}.", dir.display());
+        let (token_set, state) = load_checkpoint(&dir).unwrap_or_else(|e| {
+            eprintln!("Can't load the checkpoint:\n{}", e);
+            std::process::exit(1)
+        });
+        if token_set.processing != processing || token_set.token_type != token_type {
+            eprintln!("The checkpoint is for a different processing or token type.");
+            std::process::exit(1)
+        }
+        if state.sample_size.is_some() != min_data_size.is_some() {
+            eprintln!("The checkpoint was made with a different --min-data-size setting.");
+            std::process::exit(1)
+        }
+        if state
+            .ladder_size
+            .map_or(!sizes.is_empty(), |size| !sizes.contains(&size))
+        {
+            eprintln!("The checkpoint was made with different --sizes.");
+            std::process::exit(1)
+        }
+        if let Some(path) = &trajectory_log {
+            // The steps after the checkpoint are made and logged again.
+            or_exit(truncate_log(path, state.iteration));
+        }
+        (Some(token_set), state)
     } else {
-        None
+        let token_set = input_tokens.map(|filename| {
+            println!("Reading the input token set from {}.", filename);
+            load_token_set(filename)
+        });
+        let state = OptimizerState {
+            seed,
+            ..OptimizerState::default()
+        };
+        (token_set, state)
     };
 
     println!(
@@ -223,26 +596,68 @@ fn optimize(
         ntokens, &filename
     );
 
-    let optimizer = optimize::Optimizer::new(
-        ntokens,
-        processing,
-        token_type,
-        Some(initial_size),
-        &tokens_dir_path,
-    );
+    // The first Ctrl-C stops the run like a limit, the second one right away.
+    ctrlc::set_handler(|| {
+        if request_stop() {
+            std::process::exit(130);
+        }
+        eprintln!("Stopping after saving the token set. Press Ctrl-C again to quit now.");
+    })
+    .unwrap();
 
     let stats = if let Some(min_data_size) = min_data_size {
-        optimize_with_increasing_data(&optimizer, &filename, min_data_size, input_token_set)
+        or_exit(optimizer.optimize_with_increasing_data(
+            &filename,
+            min_data_size,
+            input_token_set,
+            state,
+        ))
     } else if initial_size < 1 << 34 {
-        optimizer.optimize(
-            &MemorySampler::from_file(&filename, 1 << 20),
+        let sampler = or_exit(MemorySampler::from_file(&filename, 1 << 20));
+        match validation_share {
+            Some(share) => {
+                let (train, validation) = or_exit(split_validation(&sampler, share, state.seed));
+                run_optimizer(
+                    &optimizer,
+                    sizes,
+                    nested,
+                    &train,
+                    Some(&validation),
+                    input_token_set,
+                    &mut state,
+                )
+            }
+            None => run_optimizer(
+                &optimizer,
+                sizes,
+                nested,
+                &sampler,
+                None,
+                input_token_set,
+                &mut state,
+            ),
+        }
+    } else {
+        if validation_share.is_some() {
+            eprintln!("Validation needs the data in memory, it's too big.");
+            std::process::exit(1)
+        }
+        run_optimizer(
+            &optimizer,
+            sizes,
+            nested,
+            &or_exit(FileSampler::new(&filename, 1 << 24, None)),
+            None,
             input_token_set,
+            &mut state,
         )
-    } else {
-        optimizer.optimize(&FileSampler::new(&filename, 1 << 24, None), input_token_set)
     };
 
-    save_tokens(&stats, tokens_dir_path);
+    // The ladder saves every token set itself.
+    if sizes.is_empty() {
+        let path = or_exit(stats.save(tokens_dir_path));
+        println!("Wrote the token set to {}.", path.display());
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -252,6 +667,7 @@ struct Args {
 }
 
 #[derive(Subcommand, Debug)]
+#[allow(clippy::large_enum_variant)]
 enum Command {
     Process {
         #[arg(short, long)]
@@ -280,6 +696,114 @@ enum Command {
         tokens_dir: String,
     },
 
+    /// Writes the token IDs of the data file as a binary array of u16 (if
+    /// there are at most 65536 tokens) or u32, with a JSON index of paragraph
+    /// offsets next to it.
+    Tokenize {
+        #[arg(short, long)]
+        data: String,
+
+        #[arg(long)]
+        processed_data: Option<String>,
+
+        #[arg(short, long)]
+        input_tokens: String,
+
+        #[arg(short, long)]
+        output: String,
+    },
+
+    /// Restores the text from token IDs, either in a binary file written by
+    /// `tokenize` or in a JSON list.
+    Detokenize {
+        #[arg(short, long)]
+        data: String,
+
+        /// The data is a JSON list of token IDs.
+        #[arg(long)]
+        json: bool,
+
+        #[arg(short, long)]
+        input_tokens: String,
+
+        #[arg(short, long)]
+        output: String,
+    },
+
+    /// Exports the token set to a HuggingFace `tokenizer.json`, reporting
+    /// what the exported BPE tokenizer can't reproduce.
+    ExportHf {
+        #[arg(short, long)]
+        input_tokens: String,
+
+        #[arg(short, long)]
+        output: String,
+    },
+
+    /// Exports the token set to a tiktoken rank file, ranking the tokens by
+    /// their counts on the data.
+    ExportTiktoken {
+        #[arg(short, long)]
+        data: String,
+
+        #[arg(long)]
+        processed_data: Option<String>,
+
+        #[arg(short, long)]
+        input_tokens: String,
+
+        #[arg(short, long)]
+        output: String,
+
+        /// Report how often the greedy rank-merge encoding of the data
+        /// differs from the optimal one.
+        #[arg(long)]
+        check: bool,
+    },
+
+    /// Imports the vocabulary of another tokenizer as a `bytes` token set
+    /// with `raw` processing. Tokens that don't stand for text are skipped, and
+    /// missing single bytes are added.
+    Import {
+        #[arg(short, long)]
+        format: VocabFormat,
+
+        #[arg(short, long)]
+        input: String,
+
+        #[arg(short, long)]
+        output: String,
+
+        /// Data to tokenize with the vocabulary. Tokenization is optimal, so
+        /// the stats differ from those of the original tokenizer.
+        #[arg(short, long)]
+        data: Option<String>,
+    },
+
+    /// Tokenizes each data file with each token set and prints the number of
+    /// tokens, raw bytes per token, tokens per word, the share of bytes
+    /// encoded by sequences of ext tokens and the share of unused tokens.
+    Compare {
+        #[arg(short, long, required = true, num_args = 1..)]
+        input_tokens: Vec<String>,
+
+        #[arg(short, long, required = true, num_args = 1..)]
+        data: Vec<String>,
+
+        /// A directory where the stats of token sets on the data are stored,
+        /// so that comparing them again is instant.
+        #[arg(long)]
+        cache_dir: Option<String>,
+    },
+
+    /// Checks token set files: all bytes are encodable, no ext token is
+    /// unreachable, no sequence shadows a token, and the recorded number of
+    /// tokens is correct.
+    Validate {
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+
     Optimize {
         #[arg(short, long)]
         data: String,
@@ -296,8 +820,18 @@ enum Command {
         #[arg(id = "type", long)]
         token_type: TokenType,
 
-        #[arg(short, long)]
-        ntokens: usize,
+        #[arg(short, long, required_unless_present = "sizes")]
+        ntokens: Option<usize>,
+
+        /// Optimize token sets of all these sizes, e.g. 256,512,1024, each
+        /// one starting from the previous one, and save every one of them.
+        #[arg(long, value_delimiter = ',', conflicts_with_all = ["ntokens", "min_data_size"])]
+        sizes: Vec<usize>,
+
+        /// Keep the tokens of every token set of --sizes in the bigger ones,
+        /// with the same IDs. Only for --type bytes.
+        #[arg(long, requires = "sizes")]
+        nested: bool,
 
         #[arg(short, long)]
         input_tokens: Option<String>,
@@ -307,6 +841,80 @@ enum Command {
         /// size of the sample that will be extracted from the full data.
         #[arg(long)]
         min_data_size: Option<usize>,
+
+        /// Tokenize the data by paragraphs and only retokenize the paragraphs
+        /// containing the token that was added or removed. Much faster, but
+        /// keeps a copy of the data in memory.
+        #[arg(long)]
+        incremental: bool,
+
+        /// The number of token removal candidates evaluated in parallel. All
+        /// available cores by default.
+        #[arg(long)]
+        threads: Option<usize>,
+
+        /// Log the estimated and the actual increase of the number of tokens
+        /// for every token removal that is tried.
+        #[arg(long)]
+        log_estimates: bool,
+
+        /// A directory where the stats of evaluated token sets are stored, so
+        /// that a restarted optimization doesn't tokenize the data again.
+        #[arg(long)]
+        cache_dir: Option<String>,
+
+        /// Where to write checkpoints. By default
+        /// `<tokens_dir>/tokens<ntokens>_<processing>_<type>.checkpoint`.
+        #[arg(long)]
+        checkpoint_dir: Option<String>,
+
+        /// The minimum number of seconds between checkpoints.
+        #[arg(long, default_value_t = 60)]
+        checkpoint_interval: u64,
+
+        /// Continue the run from its checkpoint, with the same token removal
+        /// history and data sample.
+        #[arg(long, conflicts_with = "input_tokens")]
+        resume: bool,
+
+        /// Append every accepted step to this file as a line of JSON, with
+        /// the move type, the added and removed tokens, the total number of
+        /// tokens before and after, bytes per token, the number of candidates
+        /// tried and the time since the start.
+        #[arg(long)]
+        trajectory_log: Option<String>,
+
+        /// Hold out this share of the paragraphs of the data for validation.
+        /// Bytes per token on them are tracked after every step, and the
+        /// token set with the best ones is the result.
+        #[arg(long, value_parser = parse_share)]
+        validation_share: Option<f64>,
+
+        /// Stop when the bytes per token on the held-out paragraphs haven't
+        /// improved for this many steps.
+        #[arg(long, requires = "validation_share")]
+        patience: Option<usize>,
+
+        /// Seeds the choice of the held-out paragraphs.
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+
+        /// Stop after this many seconds, counting the time before the run
+        /// was resumed, and save the best token set so far. Only applies
+        /// once the token set has the target number of tokens.
+        #[arg(long)]
+        max_time: Option<u64>,
+
+        /// Stop after this many accepted steps, counting the steps before the
+        /// run was resumed. Only applies once the token set has the target
+        /// number of tokens.
+        #[arg(long)]
+        max_steps: Option<u64>,
+
+        /// Stop when a step at the target number of tokens reduces the total
+        /// number of tokens by less than this share, e.g. 0.0001.
+        #[arg(long)]
+        min_relative_gain: Option<f64>,
     },
 }
 
@@ -321,6 +929,54 @@ fn main() {
             tokens_dir,
         } => load_save_tokens(data, processed_data.as_deref(), input_tokens, tokens_dir),
 
+        Command::Tokenize {
+            data,
+            processed_data,
+            input_tokens,
+            output,
+        } => tokenize(data, processed_data.as_deref(), input_tokens, output),
+
+        Command::Detokenize {
+            data,
+            json,
+            input_tokens,
+            output,
+        } => detokenize(data, *json, input_tokens, output),
+
+        Command::ExportHf {
+            input_tokens,
+            output,
+        } => export_hf(input_tokens, output),
+
+        Command::ExportTiktoken {
+            data,
+            processed_data,
+            input_tokens,
+            output,
+            check,
+        } => export_tiktoken(
+            data,
+            processed_data.as_deref(),
+            input_tokens,
+            output,
+            *check,
+        ),
+
+        Command::Import {
+            format,
+            input,
+            output,
+            data,
+        } => import_vocabulary(*format, input, output, data.as_deref()),
+
+        Command::Compare {
+            input_tokens,
+            data,
+            cache_dir,
+        } => compare(input_tokens, data, cache_dir.as_deref()),
+
+        Command::Validate { files } => validate(files),
+
         Command::Optimize {
             data,
             processed_data,
@@ -328,10 +984,28 @@ fn main() {
             processing,
             token_type,
             ntokens,
+            sizes,
+            nested,
             input_tokens,
             min_data_size,
+            incremental,
+            threads,
+            log_estimates,
+            cache_dir,
+            checkpoint_dir,
+            checkpoint_interval,
+            resume,
+            trajectory_log,
+            validation_share,
+            patience,
+            seed,
+            max_time,
+            max_steps,
+            min_relative_gain,
         } => optimize(
-            *ntokens,
+            ntokens.or(sizes.last().copied()).unwrap(),
+            sizes,
+            *nested,
             data,
             processed_data.as_deref(),
             tokens_dir,
@@ -339,6 +1013,23 @@ fn main() {
             *token_type,
             input_tokens.as_deref(),
             *min_data_size,
+            *resume,
+            *seed,
+            OptimizerOptions {
+                incremental: *incremental,
+                threads: threads.unwrap_or(OptimizerOptions::default().threads),
+                log_estimates: *log_estimates,
+                cache_dir: cache_dir.as_ref().map(PathBuf::from),
+                checkpoint_dir: checkpoint_dir.as_ref().map(PathBuf::from),
+                checkpoint_interval: Duration::from_secs(*checkpoint_interval),
+                trajectory_log: trajectory_log.as_ref().map(PathBuf::from),
+                validation_share: *validation_share,
+                patience: *patience,
+                max_time: max_time.map(Duration::from_secs),
+                max_steps: *max_steps,
+                min_relative_gain: *min_relative_gain,
+                verbose: true,
+            },
         ),
 
         Command::Process { data, output } => process(data.as_str(), output.as_str()),
@@ -0,0 +1,284 @@
+//! Conversion of the older token set JSON layouts to the current one.
+//!
+//! Files without a `version` field are in one of the legacy dialects:
+//!
+//! * The current layout, before the version was added.
+//! * The layout written by the old `tokens::TokenSet`, with the types
+//!   `fallback_bits` (or `str_with_fallback_bits`) with a `fallback_bits`
+//!   field, `all_tokens`, `fallback16` and `fallback_distribution` with a
+//!   `literal_count` field.
+//! * The layout written by `chars::CharsTokenSet`, with the type `chars` and
+//!   multi-token encodings of characters in an `encodings` object.
+
+use serde_json::{json, Map, Value};
+
+use super::tokenset::{ProblemKind, TokenSetError, TokenSetProblem};
+
+/// The version of the token set JSON layout written by `TokenSet::to_json`.
+pub const TOKEN_SET_VERSION: u64 = 1;
+
+/// Converts a token set JSON in any known layout to the current one.
+pub fn migrate(mut value: Value) -> Result<Value, TokenSetError> {
+    let fields = match value.as_object_mut() {
+        Some(fields) => fields,
+        // Reported by `TokenSet::from_json`.
+        None => return Ok(value),
+    };
+
+    match fields.get("version") {
+        None => {}
+        Some(v) if v.as_u64() == Some(TOKEN_SET_VERSION) => return Ok(value),
+        Some(_) => {
+            return Err(error(
+                "$.version",
+                ProblemKind::InvalidValue {
+                    expected: "a known version",
+                },
+            ))
+        }
+    }
+
+    match fields.get("type").and_then(|t| t.as_str()) {
+        Some("fallback_bits") | Some("str_with_fallback_bits") => migrate_fallback_bits(fields)?,
+        Some("all_tokens") => {
+            fields.insert("type".to_string(), "bytes".into());
+        }
+        Some("fallback16") => migrate_fallback16(fields),
+        Some("fallback_distribution") => migrate_fallback_distribution(fields),
+        Some("chars") => migrate_chars(fields)?,
+        _ => {}
+    }
+    // The old token sets didn't write the processing, the data was raw.
+    fields.entry("processing").or_insert_with(|| "raw".into());
+    fields.insert("version".to_string(), TOKEN_SET_VERSION.into());
+
+    Ok(value)
+}
+
+fn error(path: &str, kind: ProblemKind) -> TokenSetError {
+    TokenSetError {
+        problems: vec![TokenSetProblem::new(path, kind)],
+    }
+}
+
+fn migrate_fallback_bits(fields: &mut Map<String, Value>) -> Result<(), TokenSetError> {
+    let token_type = match fields.remove("fallback_bits").and_then(|v| v.as_u64()) {
+        Some(1) => "bits1",
+        Some(2) => "bits2",
+        Some(4) => "bits4",
+        _ => {
+            return Err(error(
+                "$.fallback_bits",
+                ProblemKind::InvalidValue {
+                    expected: "1, 2 or 4",
+                },
+            ))
+        }
+    };
+    fields.insert("type".to_string(), token_type.into());
+    Ok(())
+}
+
+/// The single-byte strings of the tokens or sequences in `values`.
+fn single_bytes<'a>(values: impl Iterator<Item = &'a Value>) -> Vec<u8> {
+    values
+        .filter_map(|v| match v {
+            Value::String(s) if s.len() == 1 => Some(s.as_bytes()[0]),
+            Value::Array(a) if a.len() == 1 => a[0].as_u64().filter(|&b| b < 256).map(|b| b as u8),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Unknown bytes were encoded as '\x10' followed by two hexadecimal digits.
+/// They are now encoded as the '\x10' token followed by two of 16 new ext
+/// tokens, which keeps the cost of 3 tokens per byte.
+fn migrate_fallback16(fields: &mut Map<String, Value>) {
+    let old_tokens = match fields.get("tokens").and_then(|t| t.as_array()) {
+        Some(tokens) => tokens.clone(),
+        None => return,
+    };
+
+    let mut known = [false; 256];
+    for b in single_bytes(old_tokens.iter()) {
+        known[b as usize] = true;
+    }
+
+    let mut tokens = (0..16).map(Value::from).collect::<Vec<_>>();
+    tokens.extend(old_tokens);
+
+    let sequences = (0..=255u8)
+        .filter(|&b| !known[b as usize])
+        .map(|b| json!({"string": [b], "tokens": ["\x10", b >> 4, b & 15]}))
+        .collect::<Vec<_>>();
+
+    fields.insert("type".to_string(), "byteshuff".into());
+    fields.insert("tokens".to_string(), tokens.into());
+    fields.insert("sequences".to_string(), sequences.into());
+}
+
+/// A single reserved token stood for any unknown byte, with the cost of 8
+/// tokens. The closest current type with the same cost is `bits1`.
+fn migrate_fallback_distribution(fields: &mut Map<String, Value>) {
+    fields.remove("literal_count");
+
+    if let Some(tokens) = fields.get_mut("tokens").and_then(|t| t.as_array_mut()) {
+        tokens.retain(|t| !t.is_number());
+        tokens.splice(0..0, [0.into(), 1.into()]);
+    }
+    fields.insert("type".to_string(), "bits1".into());
+}
+
+/// The encodings of characters become sequences of a `byteshuff` token set.
+/// The chars format couldn't encode the bytes that aren't part of a known
+/// character, so single-byte tokens are added for them after the existing
+/// tokens.
+fn migrate_chars(fields: &mut Map<String, Value>) -> Result<(), TokenSetError> {
+    let sequences = match fields.remove("encodings") {
+        None => Vec::new(),
+        Some(Value::Object(encodings)) => encodings
+            .into_iter()
+            .map(|(ch, tokens)| json!({"string": ch, "tokens": tokens}))
+            .collect::<Vec<_>>(),
+        Some(_) => {
+            return Err(error(
+                "$.encodings",
+                ProblemKind::InvalidValue {
+                    expected: "an object",
+                },
+            ))
+        }
+    };
+
+    let mut known = [false; 256];
+    let token_values = fields.get("tokens").and_then(|t| t.as_array());
+    for b in single_bytes(token_values.into_iter().flatten()) {
+        known[b as usize] = true;
+    }
+    for b in single_bytes(sequences.iter().map(|s| &s["string"])) {
+        known[b as usize] = true;
+    }
+
+    if let Some(tokens) = fields.get_mut("tokens").and_then(|t| t.as_array_mut()) {
+        tokens.extend(
+            (0..=255u8)
+                .filter(|&b| !known[b as usize])
+                .map(|b| json!([b])),
+        );
+    }
+
+    fields.insert("type".to_string(), "byteshuff".into());
+    if !sequences.is_empty() {
+        fields.insert("sequences".to_string(), sequences.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processing::Processing;
+    use crate::tokenset::{Token, TokenSet, TokenType};
+
+    #[test]
+    fn current() {
+        let token_set = TokenSet::new_bits4(Processing::Raw, true);
+        let value = token_set.to_json();
+        assert_eq!(value["version"], TOKEN_SET_VERSION);
+        assert_eq!(migrate(value.clone()).unwrap(), value);
+
+        let mut unversioned = value.clone();
+        unversioned.as_object_mut().unwrap().remove("version");
+        assert_eq!(migrate(unversioned).unwrap(), value);
+
+        let mut future = value;
+        future["version"] = (TOKEN_SET_VERSION + 1).into();
+        assert!(migrate(future).is_err());
+    }
+
+    #[test]
+    fn fallback_bits() {
+        let value = json!({
+            "type": "fallback_bits",
+            "fallback_bits": 2,
+            "tokens": [0, 1, 2, 3, "ab"],
+        });
+        let token_set = TokenSet::from_json(value).unwrap();
+        assert_eq!(token_set.token_type, TokenType::Bits2);
+        assert_eq!(token_set.processing, Processing::Raw);
+        assert_eq!(token_set.ntokens(), 5);
+
+        let value = json!({
+            "type": "str_with_fallback_bits",
+            "fallback_bits": 3,
+            "tokens": [],
+        });
+        assert!(TokenSet::from_json(value).is_err());
+    }
+
+    #[test]
+    fn all_tokens() {
+        let tokens = (0..=255u8).map(|b| json!([b])).collect::<Vec<_>>();
+        let value = json!({"type": "all_tokens", "processing": "capswords", "tokens": tokens});
+        let token_set = TokenSet::from_json(value).unwrap();
+        assert_eq!(token_set.token_type, TokenType::Bytes);
+        assert_eq!(token_set.ntokens(), 256);
+    }
+
+    #[test]
+    fn fallback16() {
+        let mut tokens = vec![json!("\x10")];
+        tokens.extend("0123456789abcdef".chars().map(|c| json!(c.to_string())));
+        tokens.push(json!("the"));
+        let value = json!({"type": "fallback16", "tokens": tokens});
+
+        let token_set = TokenSet::from_json(value).unwrap();
+        assert_eq!(token_set.token_type, TokenType::BytesHuff);
+        assert_eq!(token_set.ntokens(), 16 + 18);
+        assert_eq!(token_set.sequences.len(), 256 - 17);
+
+        let escape = token_set.find_token(b"\x10").unwrap();
+        let seq = token_set
+            .sequences
+            .iter()
+            .find(|s| s.string == b"z")
+            .unwrap();
+        assert_eq!(seq.tokens, vec![escape, 7, 10]);
+    }
+
+    #[test]
+    fn fallback_distribution() {
+        let value = json!({
+            "type": "fallback_distribution",
+            "tokens": [0, "a", "bc"],
+            "literal_count": vec![1; 256],
+            "stats": {"ntokens": 3},
+        });
+        let token_set = TokenSet::from_json(value).unwrap();
+        assert_eq!(token_set.token_type, TokenType::Bits1);
+        assert_eq!(token_set.ntokens(), 4);
+        assert_eq!(token_set.to_json()["type"], "bits1");
+    }
+
+    #[test]
+    fn chars() {
+        let value = json!({
+            "type": "chars",
+            "tokens": [0, 1, "a", "é", "ab"],
+            "encodings": {"b": ["a", 1], "ё": ["é", 0]},
+        });
+        let token_set = TokenSet::from_json(value).unwrap();
+        assert_eq!(token_set.token_type, TokenType::BytesHuff);
+        assert_eq!(token_set.processing, Processing::Raw);
+        assert_eq!(token_set.tokens[4], Token::Str(b"ab".to_vec()));
+        // All bytes but "a" and "b" get their own tokens.
+        assert_eq!(token_set.ntokens(), 5 + 254);
+
+        let seq = token_set
+            .sequences
+            .iter()
+            .find(|s| s.string == b"b")
+            .unwrap();
+        assert_eq!(seq.tokens, vec![2, 1]);
+    }
+}
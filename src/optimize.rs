@@ -1,17 +1,62 @@
-use std::collections::{HashMap, HashSet};
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fmt;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, Instant};
 
-use crate::batch_tokenize::{TokenizerCache, tokenize_file};
+use crate::batch_tokenize::{tokenize_file, TokenizerCache};
+use crate::checkpoint::{
+    log_step, save_checkpoint, tokenset_diff, BestValidation, MoveType, OptimizerState, StopReason,
+    TrajectoryStep,
+};
+use crate::input::file_sampler::FileSampler;
+use crate::input::memory_sampler::MemorySampler;
 use crate::input::sample::Sampler;
 use crate::optimize_bytes::{
     BytesOptimizer, HuffOptimizer, NoopBytesOptimizer, SimpleBytesOptimizer,
 };
 use crate::processing::Processing;
 use crate::stats2::TokenStats;
+use crate::tokenizer2::FragmentTokenizer;
 use crate::tokenset::{show_bytes, Token, TokenSet, TokenType};
 
+/// Prints the progress of the run if `options.verbose` is set.
+macro_rules! progress {
+    ($options:expr) => {
+        if $options.verbose {
+            println!();
+        }
+    };
+    ($options:expr, $($arg:tt)*) => {
+        if $options.verbose {
+            println!($($arg)*);
+        }
+    };
+}
+
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Asks the running optimization to stop as soon as possible and to save its
+/// result. Can be called from a signal handler. Returns whether a stop was
+/// requested before.
+pub fn request_stop() -> bool {
+    STOP_REQUESTED.swap(true, Ordering::SeqCst)
+}
+
+/// Whether the run has to stop before evaluating more candidates, because a
+/// stop was requested or the time is up.
+fn interrupted(deadline: Option<Instant>) -> Option<StopReason> {
+    if STOP_REQUESTED.load(Ordering::SeqCst) {
+        Some(StopReason::Interrupted)
+    } else if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        Some(StopReason::MaxTime)
+    } else {
+        None
+    }
+}
+
 fn is_valid_token(s: &[u8]) -> bool {
     let n = "\n".as_bytes()[0];
     for i in 0..(s.len() - 2) {
@@ -24,46 +69,32 @@ fn is_valid_token(s: &[u8]) -> bool {
 }
 
 fn show_tokenset_diff(before: &TokenSet, after: &TokenSet) -> String {
-    let mut before_set = HashSet::new();
-    let mut after_set = HashSet::new();
-
-    for token in before.tokens.iter() {
-        before_set.insert(token.clone());
-    }
-    for token in after.tokens.iter() {
-        after_set.insert(token.clone());
-    }
-
-    let mut removed = before_set
-        .difference(&after_set)
-        .map(|t| t.to_string())
-        .collect::<Vec<_>>();
-    removed.sort();
-    let mut added = after_set
-        .difference(&before_set)
-        .map(|t| t.to_string())
-        .collect::<Vec<_>>();
-    added.sort();
-
-    format!("{} -> {}", removed.join(" "), added.join(" "))
+    let (removed, added) = tokenset_diff(before, after);
+    let show = |tokens: Vec<Token>| {
+        let mut strings = tokens.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        strings.sort();
+        strings.join(" ")
+    };
+    format!("{} -> {}", show(removed), show(added))
 }
 
 fn add_token_bpe(stats: &TokenStats) -> Option<(TokenSet, i64)> {
-    let mut pairs = (0..stats.pair_counts.len())
-        .filter(|&i| stats.pair_counts[i] > 0)
-        .collect::<Vec<usize>>();
-    pairs.sort_by_key(|&i| -(stats.pair_counts[i] as i64));
+    // The most frequent pairs first, ties broken by token IDs.
+    let mut pairs = stats
+        .pair_counts
+        .iter()
+        .filter(|(_, &count)| count > 0)
+        .map(|(&pair, &count)| (Reverse(count), pair))
+        .collect::<Vec<_>>();
+    pairs.sort_unstable();
 
     let mut new_token = Vec::new();
     let mut token_count = 0;
 
-    for &pair_idx in pairs.iter() {
-        let itoken1 = pair_idx / stats.ntokens();
-        let itoken2 = pair_idx % stats.ntokens();
-
+    for &(Reverse(count), (itoken1, itoken2)) in pairs.iter() {
         new_token = match (
-            &stats.token_set.tokens[itoken1],
-            &stats.token_set.tokens[itoken2],
+            &stats.token_set.tokens[itoken1 as usize],
+            &stats.token_set.tokens[itoken2 as usize],
         ) {
             (Token::Str(s1), Token::Str(s2)) => {
                 s1.iter().chain(s2.iter()).cloned().collect::<Vec<u8>>()
@@ -72,7 +103,7 @@ fn add_token_bpe(stats: &TokenStats) -> Option<(TokenSet, i64)> {
         };
 
         if is_valid_token(&new_token) {
-            token_count = stats.pair_counts[pair_idx];
+            token_count = count;
             break;
         }
     }
@@ -139,7 +170,7 @@ fn add_byte<BO: BytesOptimizer>(
 ) -> Option<(TokenSet, i64)> {
     let old_count = count_tokens_in_bytes(&stats.token_set, stats);
     let new_tokenset = BO::optimize_bytes(
-        &stats,
+        stats,
         stats.token_set.ntokens() - stats.token_set.n_long_tokens() + 1,
     );
 
@@ -151,15 +182,14 @@ fn add_byte<BO: BytesOptimizer>(
     Some((new_tokenset, old_count as i64 - new_count as i64))
 }
 
-fn add_token<'a, S: Sampler<'a>, BO: BytesOptimizer>(
-    tokenset: &TokenSet,
+/// Chooses the token set with one more token, either a byte or the merge of
+/// the most frequent pair, whichever saves more tokens.
+fn choose_addition<BO: BytesOptimizer>(
+    stats: &TokenStats,
     bytes_optimizer: &BO,
-    tokenizer_cache: &mut TokenizerCache<'a, S>,
 ) -> Option<TokenSet> {
-    let stats = tokenizer_cache.get_stats_with_pairs(tokenset);
-
-    let maybe_tokenset_byte = add_byte(&stats, bytes_optimizer);
-    let maybe_tokenset_token = add_token_bpe(&stats);
+    let maybe_tokenset_byte = add_byte(stats, bytes_optimizer);
+    let maybe_tokenset_token = add_token_bpe(stats);
 
     match (maybe_tokenset_byte, maybe_tokenset_token) {
         (None, None) => None,
@@ -175,21 +205,143 @@ fn add_token<'a, S: Sampler<'a>, BO: BytesOptimizer>(
     }
 }
 
-fn remove_add_token<'a, S: Sampler<'a>, BO: BytesOptimizer>(
+fn add_token<'a, S: Sampler<'a>, BO: BytesOptimizer>(
+    tokenset: &TokenSet,
+    bytes_optimizer: &BO,
+    tokenizer_cache: &mut TokenizerCache<'a, S>,
+) -> Option<TokenSet> {
+    let stats = tokenizer_cache.get_stats_with_pairs(tokenset);
+    choose_addition(&stats, bytes_optimizer)
+}
+
+/// The estimated cost of a removal ignores the context of the token, where a
+/// better encoding can be found, so it can be several times higher than the
+/// actual cost. Candidates are only skipped if the estimate exceeds the
+/// possible gain, the best addition plus the count of the removed token, by
+/// this factor.
+const REMOVAL_ESTIMATE_MARGIN: u64 = 4;
+
+/// Estimates by how many tokens the total would grow without each multi-byte
+/// token: its count times the number of extra tokens needed to encode its
+/// string without it. Returns the strings of the tokens with their counts and
+/// the estimates.
+fn estimate_removal_costs(stats: &TokenStats) -> Vec<(Vec<u8>, u64, u64)> {
+    let tokenizer = FragmentTokenizer::new(stats.token_set.clone());
+    let mut cost_state = Vec::new();
+
+    stats
+        .token_set
+        .tokens
+        .iter()
+        .zip(stats.token_counts.iter())
+        .filter_map(|(token, &count)| match token {
+            Token::Str(s) if s.len() > 1 => {
+                let extra = tokenizer.split_cost(s, &mut cost_state) - 1;
+                Some((s.clone(), count, count * extra))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Removes the token `removed` and adds the best token instead. Returns the
+/// stats after the removal and, if a token could be added, after the addition.
+/// Doesn't update the cache, so that candidates can be evaluated in parallel.
+fn evaluate_removal<'a, S: Sampler<'a>, BO: BytesOptimizer>(
+    token_set: &TokenSet,
+    removed: &[u8],
+    bytes_optimizer: &BO,
+    tokenizer_cache: &TokenizerCache<'a, S>,
+) -> (TokenStats, Option<TokenStats>) {
+    let mut new_token_set = token_set.clone();
+    let token_idx = new_token_set.find_token(removed).unwrap();
+    new_token_set.remove_token(token_idx);
+    assert!(new_token_set.ntokens() == token_set.ntokens() - 1);
+
+    let stats = tokenizer_cache.compute_stats_with_pairs(&new_token_set, None);
+    let newer_stats = choose_addition(&stats, bytes_optimizer)
+        .map(|newer_token_set| tokenizer_cache.compute_stats(&newer_token_set, Some(&stats)));
+
+    (stats, newer_stats)
+}
+
+/// Evaluates the removal candidates in `batch` like `evaluate_removal`, but
+/// together, so that the data is read once for all of them. The results are
+/// in the order of the candidates.
+fn evaluate_removals<'a, S: Sampler<'a> + Sync, BO: BytesOptimizer + Sync>(
+    token_set: &TokenSet,
+    batch: &[Vec<u8>],
+    bytes_optimizer: &BO,
+    tokenizer_cache: &TokenizerCache<'a, S>,
+) -> Vec<(TokenStats, Option<TokenStats>)> {
+    if batch.len() == 1 {
+        return vec![evaluate_removal(
+            token_set,
+            &batch[0],
+            bytes_optimizer,
+            tokenizer_cache,
+        )];
+    }
+
+    let removed_sets = batch
+        .iter()
+        .map(|removed| {
+            let mut new_token_set = token_set.clone();
+            let token_idx = new_token_set.find_token(removed).unwrap();
+            new_token_set.remove_token(token_idx);
+            new_token_set
+        })
+        .collect::<Vec<_>>();
+    let stats = tokenizer_cache.compute_stats_batch(&removed_sets, &vec![None; batch.len()], true);
+
+    let additions = std::thread::scope(|scope| {
+        let handles = stats
+            .iter()
+            .map(|stats| scope.spawn(|| choose_addition(stats, bytes_optimizer)))
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+
+    let added = (0..batch.len())
+        .filter(|&i| additions[i].is_some())
+        .collect::<Vec<_>>();
+    let added_sets = additions.into_iter().flatten().collect::<Vec<_>>();
+    let related = added.iter().map(|&i| Some(&stats[i])).collect::<Vec<_>>();
+    let mut newer_stats = tokenizer_cache
+        .compute_stats_batch(&added_sets, &related, false)
+        .into_iter();
+
+    let mut results = stats.into_iter().map(|s| (s, None)).collect::<Vec<_>>();
+    for i in added {
+        results[i].1 = newer_stats.next();
+    }
+    results
+}
+
+fn remove_add_token<'a, S: Sampler<'a> + Sync, BO: BytesOptimizer + Sync>(
     token_set: &TokenSet,
     ntokens: usize,
     bytes_optimizer: &BO,
     tokenizer_cache: &mut TokenizerCache<'a, S>,
-    removal_count: &mut HashMap<Vec<u8>, usize>,
-) -> Option<TokenStats> {
+    state: &mut OptimizerState,
+    options: &OptimizerOptions,
+    deadline: Option<Instant>,
+) -> Result<Step, StopReason> {
     if token_set.ntokens() < ntokens {
         if let Some(new_tokenset) = add_token(token_set, bytes_optimizer, tokenizer_cache) {
             let stats = tokenizer_cache.get_stats(&new_tokenset);
-            println!("{}", show_tokenset_diff(token_set, &new_tokenset));
-            println!("processed bytes / token: {}", stats.bytes_per_token());
-            return Some(stats);
+            progress!(options, "{}", show_tokenset_diff(token_set, &new_tokenset));
+            progress!(
+                options,
+                "processed bytes / token: {}",
+                stats.bytes_per_token()
+            );
+            return Ok(Step::new(stats, MoveType::Add, 1));
         } else {
-            return None;
+            return Err(StopReason::Converged);
         }
     }
 
@@ -204,75 +356,180 @@ fn remove_add_token<'a, S: Sampler<'a>, BO: BytesOptimizer>(
         if let Some((new_token_set, _)) = add_token_bpe(&new_stats) {
             let new_stats = tokenizer_cache.get_stats(&new_token_set);
             if new_stats.total_tokens < stats.total_tokens {
-                println!("{}", show_tokenset_diff(token_set, &new_token_set));
-                println!("processed bytes / token: {}", new_stats.bytes_per_token());
-                return Some(new_stats);
+                progress!(options, "{}", show_tokenset_diff(token_set, &new_token_set));
+                progress!(
+                    options,
+                    "processed bytes / token: {}",
+                    new_stats.bytes_per_token()
+                );
+                return Ok(Step::new(new_stats, MoveType::BytesAdd, 1));
             }
         }
     }
 
-    let mut to_remove = vec![];
-    for token in token_set.tokens.iter() {
-        if let Token::Str(s) = token {
-            if s.len() > 1 {
-                to_remove.push(s.clone())
+    // A removal can only pay off if it costs fewer tokens than adding a token
+    // saves. The added token can save about as many tokens as the best
+    // addition now, plus the count of the removed token, whose occurrences it
+    // can absorb. The candidates with the least estimated cost come first,
+    // and among equal ones those tried less often in the previous steps.
+    let pair_stats = tokenizer_cache.get_stats_with_pairs(token_set);
+    let max_gain = [
+        add_token_bpe(&pair_stats).map(|(_, gain)| gain),
+        add_byte(&pair_stats, bytes_optimizer).map(|(_, gain)| gain),
+    ]
+    .into_iter()
+    .flatten()
+    .max()
+    .unwrap_or(0);
+
+    // The tokens of the smaller token sets of a nested ladder stay.
+    let fixed = state.fixed_tokens.iter().collect::<HashSet<_>>();
+    let mut candidates = estimate_removal_costs(&pair_stats);
+    candidates.retain(|(s, _, _)| !fixed.contains(&Token::Str(s.clone())));
+    let ncandidates = candidates.len();
+    candidates.retain(|&(_, count, estimate)| {
+        estimate < REMOVAL_ESTIMATE_MARGIN * (max_gain.max(0) as u64 + count)
+    });
+    candidates.sort_by_cached_key(|(s, _, estimate)| {
+        (
+            *estimate,
+            *state.removal_count.get(s).unwrap_or(&0),
+            s.clone(),
+        )
+    });
+    let (to_remove, estimates): (Vec<_>, Vec<_>) = candidates
+        .into_iter()
+        .map(|(s, _, estimate)| (s, estimate))
+        .unzip();
+
+    progress!(
+        options,
+        "{} of {} tokens might be worth replacing, the best addition saves {} tokens.",
+        to_remove.len(),
+        ncandidates,
+        max_gain
+    );
+
+    // The candidates are evaluated in batches, but the first improving one is
+    // taken, as if they were evaluated one by one.
+    if options.verbose {
+        print!("Removing:");
+    }
+    let mut tried = 0;
+    let batch_size = options.threads.max(1);
+    for (batch, batch_estimates) in to_remove
+        .chunks(batch_size)
+        .zip(estimates.chunks(batch_size))
+    {
+        if let Some(reason) = interrupted(deadline) {
+            progress!(options);
+            return Err(reason);
+        }
+        if options.verbose && !options.log_estimates {
+            for s in batch {
+                print!(" {}", show_bytes(s.as_slice()));
             }
+            std::io::stdout().flush().unwrap();
         }
-    }
-    to_remove.sort_unstable_by_key(|s| removal_count.get(s).unwrap_or(&0));
 
-    print!("Removing:");
-    for s in to_remove {
-        *removal_count.entry(s.clone()).or_insert(0) += 1;
-        let mut new_token_set = token_set.clone();
-        print!(" {}", show_bytes(s.as_slice()));
-        std::io::stdout().flush().unwrap();
-        let token_idx = new_token_set.find_token(&s).unwrap();
-        new_token_set.remove_token(token_idx);
-        assert!(new_token_set.ntokens() == ntokens - 1);
+        let results = evaluate_removals(token_set, batch, bytes_optimizer, tokenizer_cache);
+
+        for ((s, estimate), (new_stats, newer_stats)) in
+            batch.iter().zip(batch_estimates).zip(results)
+        {
+            *state.removal_count.entry(s.clone()).or_insert(0) += 1;
+            tried += 1;
+            tokenizer_cache.insert(&new_stats);
+            if options.verbose && options.log_estimates {
+                print!(
+                    "\n  {}: estimated +{}, actual {:+}",
+                    show_bytes(s.as_slice()),
+                    estimate,
+                    new_stats.total_tokens as i64 - stats.total_tokens as i64
+                );
+            }
 
-        if let Some(newer_tokenset) = add_token(&new_token_set, bytes_optimizer, tokenizer_cache) {
-            let newer_stats = tokenizer_cache.get_stats(&newer_tokenset);
-            if newer_stats.total_tokens < stats.total_tokens {
-                println!();
-                println!("{}", show_tokenset_diff(token_set, &newer_tokenset));
-                println!("processed bytes / token: {}", newer_stats.bytes_per_token());
-                return Some(newer_stats);
+            if let Some(newer_stats) = newer_stats {
+                tokenizer_cache.insert(&newer_stats);
+                if newer_stats.total_tokens < stats.total_tokens {
+                    progress!(options);
+                    progress!(
+                        options,
+                        "{}",
+                        show_tokenset_diff(token_set, &newer_stats.token_set)
+                    );
+                    progress!(
+                        options,
+                        "processed bytes / token: {}",
+                        newer_stats.bytes_per_token()
+                    );
+                    return Ok(Step::new(newer_stats, MoveType::RemoveAdd, tried));
+                }
             }
         }
     }
-    println!();
+    progress!(options);
+
+    Err(StopReason::Converged)
+}
+
+/// A change of the token set accepted by `optimization_step`.
+struct Step {
+    stats: TokenStats,
+    move_type: MoveType,
+    /// The number of candidates evaluated to find the change.
+    candidates: usize,
+}
 
-    None
+impl Step {
+    fn new(stats: TokenStats, move_type: MoveType, candidates: usize) -> Self {
+        Step {
+            stats,
+            move_type,
+            candidates,
+        }
+    }
 }
 
-fn optimization_step<'a, S: Sampler<'a>, BO: BytesOptimizer>(
+fn optimization_step<'a, S: Sampler<'a> + Sync, BO: BytesOptimizer + Sync>(
     token_set: &TokenSet,
     ntokens: usize,
     bytes_optimizer: &BO,
     tokenizer_cache: &mut TokenizerCache<'a, S>,
-    removal_count: &mut HashMap<Vec<u8>, usize>,
-) -> Option<TokenSet> {
+    state: &mut OptimizerState,
+    options: &OptimizerOptions,
+    deadline: Option<Instant>,
+) -> Result<Step, StopReason> {
     let stats = tokenizer_cache.get_stats(token_set);
     let new_token_set = BO::optimize_bytes(&stats, ntokens - token_set.n_long_tokens());
     let new_stats = tokenizer_cache.get_stats(&new_token_set);
 
     if new_stats.total_tokens < stats.total_tokens {
-        println!("{}", show_tokenset_diff(token_set, &new_token_set));
-        println!("processed bytes / token: {}", new_stats.bytes_per_token());
+        progress!(options, "{}", show_tokenset_diff(token_set, &new_token_set));
+        progress!(
+            options,
+            "processed bytes / token: {}",
+            new_stats.bytes_per_token()
+        );
 
-        return Some(new_stats.token_set);
+        return Ok(Step::new(new_stats, MoveType::Bytes, 1));
     }
 
-    if let Some(new_stats) = remove_add_token(
+    match remove_add_token(
         token_set,
         ntokens,
         bytes_optimizer,
         tokenizer_cache,
-        removal_count,
+        state,
+        options,
+        deadline,
     ) {
-        assert!(new_stats.token_set.ntokens() <= ntokens);
-        return Some(new_stats.token_set);
+        Ok(step) => {
+            assert!(step.stats.token_set.ntokens() <= ntokens);
+            return Ok(step);
+        }
+        Err(StopReason::Converged) => {}
+        Err(reason) => return Err(reason),
     }
 
     // if let Some(new_stats) = add_remove_token(token_set, ntokens, bytes_optimizer, tokenizer_cache)
@@ -281,65 +538,200 @@ fn optimization_step<'a, S: Sampler<'a>, BO: BytesOptimizer>(
     //     return Some(new_stats.token_set);
     // }
 
-    None
+    Err(StopReason::Converged)
 }
 
-fn save_tokens(token_set: &TokenSet, tokens_dir: &Path) {
-    let output_path = tokens_dir.join(format!("{}.json", token_set.name()));
-    println!("Writing the token set to {}.", output_path.display());
-    let serialized = serde_json::to_string(&token_set.to_json()).unwrap();
-    std::fs::write(&output_path, serialized).unwrap();
+/// Tokenizes the held-out data with the token set and records it as the best
+/// one if it has the target size and fewer tokens than the best so far.
+/// Returns the bytes per token.
+fn validate(
+    token_set: &TokenSet,
+    ntokens: usize,
+    sampler: &MemorySampler,
+    state: &mut OptimizerState,
+) -> f64 {
+    let stats = tokenize_file(token_set, sampler, None);
+    let improved = state
+        .best_validation
+        .as_ref()
+        .is_none_or(|best| stats.total_tokens < best.total_tokens);
+    if token_set.ntokens() == ntokens && improved {
+        state.best_validation = Some(BestValidation {
+            token_set: token_set.clone(),
+            iteration: state.iteration,
+            total_tokens: stats.total_tokens,
+            bytes_per_token: stats.bytes_per_token(),
+        });
+    }
+    stats.bytes_per_token()
 }
 
-fn optimize_tokenset_impl<'a, S: Sampler<'a>, BO: BytesOptimizer>(
+#[allow(clippy::too_many_arguments)]
+fn optimize_tokenset_impl<'a, S: Sampler<'a> + Sync, BO: BytesOptimizer + Sync>(
     mut token_set: TokenSet,
     ntokens: usize,
     bytes_optimizer: &BO,
     tokenizer_cache: &mut TokenizerCache<'a, S>,
-    tokens_dir: &Path,
+    validation: Option<&MemorySampler>,
+    checkpoint_dir: &Path,
+    options: &OptimizerOptions,
+    state: &mut OptimizerState,
 ) -> TokenStats {
+    // A stop requested during an earlier run in the process is handled.
+    STOP_REQUESTED.store(false, Ordering::SeqCst);
+    if let Some(sampler) = validation {
+        validate(&token_set, ntokens, sampler, state);
+    }
+
     let stats = tokenizer_cache.get_stats(&token_set);
-    println!(
+    progress!(
+        options,
         "Initial tokens: {}, bytes/token = {}",
         token_set.ntokens(),
         stats.bytes_per_token()
     );
 
-    let mut removal_count = HashMap::new();
+    let start = Instant::now();
+    let elapsed = state.elapsed();
+    let deadline = options
+        .max_time
+        .map(|max_time| start + max_time.saturating_sub(Duration::from_secs_f64(elapsed)));
     let mut last_save = Instant::now();
 
-    loop {
-        if let Some(new_token_set) = optimization_step(
+    let stop_reason = loop {
+        // Like `min_relative_gain`, the limits only stop the remove/add
+        // phase, so that the result has the requested size.
+        let at_target = token_set.ntokens() == ntokens;
+        let deadline = deadline.filter(|_| at_target);
+        if at_target
+            && options
+                .max_steps
+                .is_some_and(|max_steps| state.iteration >= max_steps)
+        {
+            progress!(options, "Reached {} steps. Stopping.", state.iteration);
+            break StopReason::MaxSteps;
+        }
+        if let Some(reason) = interrupted(deadline) {
+            break reason;
+        }
+
+        let step = match optimization_step(
             &token_set,
             ntokens,
             bytes_optimizer,
             tokenizer_cache,
-            &mut removal_count,
+            state,
+            options,
+            deadline,
         ) {
-            token_set = new_token_set;
-            if Instant::now() - last_save > Duration::from_secs(60) {
-                save_tokens(&token_set, tokens_dir);
-                last_save = Instant::now();
+            Ok(step) => step,
+            Err(reason) => break reason,
+        };
+        let before = tokenizer_cache.get_stats(&token_set);
+        token_set = step.stats.token_set.clone();
+        state.iteration += 1;
+        let time = elapsed + start.elapsed().as_secs_f64();
+        let mut trajectory_step = TrajectoryStep::new(
+            state.iteration,
+            step.move_type,
+            &before,
+            &step.stats,
+            step.candidates,
+            time,
+        );
+        if let Some(sampler) = validation {
+            let bytes_per_token = validate(&token_set, ntokens, sampler, state);
+            progress!(options, "validation bytes / token: {}", bytes_per_token);
+            trajectory_step.validation_bytes_per_token = Some(bytes_per_token);
+        }
+        if let Some(path) = &options.trajectory_log {
+            log_step(path, &trajectory_step);
+        }
+        state.trajectory.push(trajectory_step);
+
+        if last_save.elapsed() >= options.checkpoint_interval {
+            progress!(
+                options,
+                "Writing a checkpoint to {}.",
+                checkpoint_dir.display()
+            );
+            save_checkpoint(checkpoint_dir, &token_set, state);
+            last_save = Instant::now();
+        }
+
+        let since_best = state
+            .best_validation
+            .as_ref()
+            .map(|b| state.iteration - b.iteration);
+        if let (Some(since_best), Some(patience)) = (since_best, options.patience) {
+            if since_best >= patience as u64 {
+                progress!(
+                    options,
+                    "Validation bytes / token hasn't improved for {} steps. Stopping.",
+                    since_best
+                );
+                break StopReason::Patience;
             }
-        } else {
-            break;
         }
+
+        // Only the remove/add phase is stopped, while the token set is
+        // growing every step saves many tokens.
+        if let Some(min_gain) = options.min_relative_gain {
+            let gain = 1.0 - step.stats.total_tokens as f64 / before.total_tokens as f64;
+            if before.ntokens() == ntokens && gain < min_gain {
+                progress!(
+                    options,
+                    "The step saved only {} of the tokens. Stopping.",
+                    gain
+                );
+                break StopReason::MinRelativeGain;
+            }
+        }
+    };
+    match stop_reason {
+        StopReason::MaxTime => progress!(options, "Out of time. Stopping."),
+        StopReason::Interrupted => progress!(options, "Interrupted. Stopping."),
+        _ => {}
+    }
+
+    if let Some(best) = &state.best_validation {
+        progress!(
+            options,
+            "Best validation bytes / token: {} after step {}.",
+            best.bytes_per_token,
+            best.iteration
+        );
+        token_set = best.token_set.clone();
     }
 
     token_set.sort();
-    tokenizer_cache.get_stats(&token_set).clone()
+    save_checkpoint(checkpoint_dir, &token_set, state);
+    let mut stats = tokenizer_cache.get_stats(&token_set).clone();
+    stats.stop_reason = Some(stop_reason);
+    stats
 }
 
-pub fn optimize_tokenset<'a, S: Sampler<'a>>(
+#[allow(clippy::too_many_arguments)]
+pub fn optimize_tokenset<'a, S: Sampler<'a> + Sync>(
     ntokens: usize,
     sampler: &'a S,
     processing: Processing,
     token_type: TokenType,
     initial_size: Option<u64>,
     pretrained_token_set: Option<TokenSet>,
-    tokens_dir: &Path,
+    validation: Option<&MemorySampler>,
+    checkpoint_dir: &Path,
+    options: &OptimizerOptions,
+    state: &mut OptimizerState,
 ) -> TokenStats {
-    let mut tokenizer_cache = TokenizerCache::new(sampler, initial_size);
+    let mut tokenizer_cache = if options.incremental {
+        TokenizerCache::new_incremental(sampler, initial_size)
+    } else {
+        TokenizerCache::new(sampler, initial_size)
+    };
+    if let Some(dir) = &options.cache_dir {
+        tokenizer_cache = tokenizer_cache.with_cache_dir(dir);
+    }
 
     let token_set = match (pretrained_token_set, token_type) {
         (Some(ts), _) => ts,
@@ -362,7 +754,10 @@ pub fn optimize_tokenset<'a, S: Sampler<'a>>(
                 ntokens,
                 &bytes_optimizer,
                 &mut tokenizer_cache,
-                tokens_dir,
+                validation,
+                checkpoint_dir,
+                options,
+                state,
             )
         }
         TokenType::Bytes => {
@@ -372,7 +767,10 @@ pub fn optimize_tokenset<'a, S: Sampler<'a>>(
                 ntokens,
                 &noop_bytes_optimizer,
                 &mut tokenizer_cache,
-                tokens_dir,
+                validation,
+                checkpoint_dir,
+                options,
+                state,
             )
         }
         TokenType::BytesHuff => {
@@ -382,18 +780,154 @@ pub fn optimize_tokenset<'a, S: Sampler<'a>>(
                 ntokens,
                 &bytes_optimizer,
                 &mut tokenizer_cache,
-                tokens_dir,
+                validation,
+                checkpoint_dir,
+                options,
+                state,
             )
         }
     }
 }
 
+#[derive(Debug)]
+pub enum OptimizeError {
+    Io(std::io::Error),
+    /// The validation share isn't greater than 0 and less than 1.
+    ValidationShare(f64),
+    /// No paragraph was held out for validation, or all of them were.
+    EmptyValidation,
+    /// The sizes of a ladder are empty or not increasing.
+    LadderSizes,
+    /// The state is of a resumed ladder with a size not in the sizes.
+    LadderResume(usize),
+    /// Only `TokenType::Bytes` keeps the single-byte tokens of the smaller
+    /// token sets of a nested ladder.
+    NestedTokenType(TokenType),
+}
+
+impl fmt::Display for OptimizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OptimizeError::Io(e) => write!(f, "{}", e),
+            OptimizeError::ValidationShare(share) => write!(
+                f,
+                "The validation share has to be greater than 0 and less than 1, not {}.",
+                share
+            ),
+            OptimizeError::EmptyValidation => write!(
+                f,
+                "Holding out paragraphs for validation left no data for training or for \
+                 validation, the data needs more paragraphs."
+            ),
+            OptimizeError::LadderSizes => {
+                write!(f, "The sizes have to be non-empty and increasing.")
+            }
+            OptimizeError::LadderResume(size) => write!(
+                f,
+                "The state is for a token set with {} tokens, which isn't one of the sizes.",
+                size
+            ),
+            OptimizeError::NestedTokenType(token_type) => write!(
+                f,
+                "Nested token sets need the bytes token type, not {}.",
+                token_type
+            ),
+        }
+    }
+}
+
+impl std::error::Error for OptimizeError {}
+
+impl From<std::io::Error> for OptimizeError {
+    fn from(e: std::io::Error) -> Self {
+        OptimizeError::Io(e)
+    }
+}
+
+/// Splits the data into a training and a held-out sampler with
+/// `MemorySampler::split`, checking the share and that neither of them is
+/// empty.
+pub fn split_validation(
+    sampler: &MemorySampler,
+    share: f64,
+    seed: u64,
+) -> Result<(MemorySampler, MemorySampler), OptimizeError> {
+    if !(share > 0.0 && share < 1.0) {
+        return Err(OptimizeError::ValidationShare(share));
+    }
+    let (train, validation) = sampler.split(share, seed);
+    if train.total_size() == 0 || validation.total_size() == 0 {
+        return Err(OptimizeError::EmptyValidation);
+    }
+    Ok((train, validation))
+}
+
+/// Settings of the optimizer besides the token set parameters.
+#[derive(Clone, Debug)]
+pub struct OptimizerOptions {
+    /// Use `TokenizerCache::new_incremental`.
+    pub incremental: bool,
+    /// The number of removal candidates evaluated in parallel.
+    pub threads: usize,
+    /// Log the estimated and the actual cost of every token removal.
+    pub log_estimates: bool,
+    /// Store the stats of evaluated token sets in this directory. See
+    /// `TokenizerCache::with_cache_dir`.
+    pub cache_dir: Option<PathBuf>,
+    /// Where the checkpoints are written. By default a directory named after
+    /// the target token set in the tokens directory.
+    pub checkpoint_dir: Option<PathBuf>,
+    /// The minimum time between checkpoints. One is also written at the end.
+    pub checkpoint_interval: Duration,
+    /// Append every accepted step to this file as a line of JSON. See
+    /// `TrajectoryStep::to_json`.
+    pub trajectory_log: Option<PathBuf>,
+    /// The share of paragraphs held out for validation by
+    /// `Optimizer::optimize_with_increasing_data`.
+    pub validation_share: Option<f64>,
+    /// Stop when the bytes per token on the held-out data haven't improved
+    /// for this many steps.
+    pub patience: Option<usize>,
+    /// Stop after this much time, including the time before the run was
+    /// resumed, once the token set has the target size.
+    pub max_time: Option<Duration>,
+    /// Stop after this many accepted steps, including the steps before the
+    /// run was resumed, once the token set has the target size.
+    pub max_steps: Option<u64>,
+    /// Stop when a step at the target size saves a smaller share of the
+    /// tokens.
+    pub min_relative_gain: Option<f64>,
+    /// Print the progress of the run to stdout.
+    pub verbose: bool,
+}
+
+impl Default for OptimizerOptions {
+    fn default() -> Self {
+        OptimizerOptions {
+            incremental: false,
+            threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            log_estimates: false,
+            cache_dir: None,
+            checkpoint_dir: None,
+            checkpoint_interval: Duration::from_secs(60),
+            trajectory_log: None,
+            validation_share: None,
+            patience: None,
+            max_time: None,
+            max_steps: None,
+            min_relative_gain: None,
+            verbose: false,
+        }
+    }
+}
+
 pub struct Optimizer {
     ntokens: usize,
     processing: Processing,
     token_type: TokenType,
     unprocessed_data_size: Option<u64>,
     tokens_dir: Box<Path>,
+    options: OptimizerOptions,
 }
 
 impl Optimizer {
@@ -403,29 +937,596 @@ impl Optimizer {
         token_type: TokenType,
         unprocessed_data_size: Option<u64>,
         tokens_dir: &Path,
-     ) -> Self {
+        options: OptimizerOptions,
+    ) -> Self {
         Self {
             ntokens,
             processing,
             token_type,
             unprocessed_data_size,
             tokens_dir: tokens_dir.into(),
+            options,
         }
     }
 
-    pub fn optimize<'a>(&self, sampler: &'a impl Sampler<'a>, pretrained_token_set: Option<TokenSet>) -> TokenStats {
+    pub fn optimize<'a>(
+        &self,
+        sampler: &'a (impl Sampler<'a> + Sync),
+        pretrained_token_set: Option<TokenSet>,
+    ) -> TokenStats {
+        self.optimize_with_state(
+            sampler,
+            None,
+            pretrained_token_set,
+            &mut OptimizerState::default(),
+        )
+    }
+
+    /// Like `optimize`, but continues a run from its state, e.g. loaded from
+    /// a checkpoint together with the token set. With held-out `validation`
+    /// data, returns the token set with the fewest tokens on it.
+    pub fn optimize_with_state<'a>(
+        &self,
+        sampler: &'a (impl Sampler<'a> + Sync),
+        validation: Option<&MemorySampler>,
+        token_set: Option<TokenSet>,
+        state: &mut OptimizerState,
+    ) -> TokenStats {
         optimize_tokenset(
             self.ntokens,
             sampler,
             self.processing,
             self.token_type,
             self.unprocessed_data_size,
-            pretrained_token_set,
-            &self.tokens_dir,
+            token_set,
+            validation,
+            &self.checkpoint_dir(),
+            &self.options,
+            state,
         )
     }
 
-    pub fn get_stats<'a>(&self, sampler:  &'a impl Sampler<'a>, tokenset: &TokenSet) -> TokenStats {
-        tokenize_file(tokenset, sampler, self.unprocessed_data_size)
+    /// The directory where the checkpoints of the runs are written.
+    pub fn checkpoint_dir(&self) -> PathBuf {
+        match &self.options.checkpoint_dir {
+            Some(dir) => dir.clone(),
+            None => self.tokens_dir.join(format!(
+                "tokens{}_{}_{}.checkpoint",
+                self.ntokens, self.processing, self.token_type
+            )),
+        }
+    }
+
+    pub fn get_stats<'a>(&self, sampler: &'a impl Sampler<'a>, tokenset: &TokenSet) -> TokenStats {
+        match &self.options.cache_dir {
+            Some(dir) => TokenizerCache::new(sampler, self.unprocessed_data_size)
+                .with_cache_dir(dir)
+                .get_stats(tokenset),
+            None => tokenize_file(tokenset, sampler, self.unprocessed_data_size),
+        }
+    }
+
+    /// Optimizes token sets of the increasing `sizes`, each starting from the
+    /// previous one, and saves all of them to the tokens directory. The size
+    /// of the optimizer should be the last one, its checkpoint covers the
+    /// whole ladder. With `nested`, every token set keeps the tokens of the
+    /// smaller ones, with the same IDs. Returns the stats of the last one, or
+    /// an error if the sizes are empty, not increasing or don't include the
+    /// size of a resumed `state`.
+    pub fn optimize_ladder<'a>(
+        &self,
+        sizes: &[usize],
+        nested: bool,
+        sampler: &'a (impl Sampler<'a> + Sync),
+        validation: Option<&MemorySampler>,
+        input_token_set: Option<TokenSet>,
+        state: &mut OptimizerState,
+    ) -> Result<TokenStats, OptimizeError> {
+        if sizes.is_empty() || !sizes.windows(2).all(|pair| pair[0] < pair[1]) {
+            return Err(OptimizeError::LadderSizes);
+        }
+        if let Some(size) = state.ladder_size.filter(|size| !sizes.contains(size)) {
+            return Err(OptimizeError::LadderResume(size));
+        }
+        // Other token types choose their single-byte tokens again, which
+        // could drop some of the fixed ones.
+        if nested && self.token_type != TokenType::Bytes {
+            return Err(OptimizeError::NestedTokenType(self.token_type));
+        }
+
+        let mut token_set = input_token_set;
+        let mut previous_tokens = Vec::new();
+        let mut last_stats = None;
+
+        for &size in sizes {
+            // The smaller token sets of a resumed ladder are saved already.
+            if state.ladder_size.is_some_and(|current| current > size) {
+                continue;
+            }
+
+            // Every rung starts with fresh removal counts and validation.
+            if state.ladder_size != Some(size) {
+                state.ladder_size = Some(size);
+                state.removal_count.clear();
+                state.best_validation = None;
+                if nested {
+                    state.fixed_tokens = std::mem::take(&mut previous_tokens);
+                }
+                if let Some(token_set) = token_set.as_ref() {
+                    save_checkpoint(&self.checkpoint_dir(), token_set, state);
+                }
+            }
+
+            progress!(self.options, "Optimizing a token set with {} tokens.", size);
+            let mut stats = optimize_tokenset(
+                size,
+                sampler,
+                self.processing,
+                self.token_type,
+                self.unprocessed_data_size,
+                token_set.take(),
+                validation,
+                &self.checkpoint_dir(),
+                &self.options,
+                state,
+            );
+            token_set = Some(stats.token_set.clone());
+
+            if nested {
+                stats.put_first(&state.fixed_tokens);
+                previous_tokens = stats.token_set.tokens.clone();
+            }
+            let path = stats.save(&self.tokens_dir)?;
+            progress!(self.options, "Wrote the token set to {}.", path.display());
+
+            let stopped = stats.stop_reason.is_some_and(|reason| reason.is_limit());
+            last_stats = Some(stats);
+            if stopped {
+                break;
+            }
+        }
+
+        Ok(last_stats.unwrap())
+    }
+
+    /// Optimizes the token set on samples of the data file of growing size,
+    /// starting from `min_data_size` bytes and doubling it until the stats on
+    /// the sample are close to the stats on the full data. A resumed run
+    /// continues at the stage recorded in `state`. The last stage uses all
+    /// the data.
+    pub fn optimize_with_increasing_data(
+        &self,
+        filename: &str,
+        min_data_size: usize,
+        input_token_set: Option<TokenSet>,
+        mut state: OptimizerState,
+    ) -> Result<TokenStats, OptimizeError> {
+        let full_sampler = FileSampler::new(filename, 1 << 24, None)?;
+        let full_size = full_sampler.total_size() as usize;
+        let mut tokenset = input_token_set;
+        let mut size = state.sample_size.unwrap_or(min_data_size);
+
+        let full_stats = loop {
+            let last = size >= full_size;
+            let sample = if last {
+                MemorySampler::from_file(filename, 1 << 20)?
+            } else {
+                MemorySampler::sample_from_file(filename, size, 1 << 20)?
+            };
+            let (sampler, validation) = match self.options.validation_share {
+                Some(share) => {
+                    let (train, validation) = split_validation(&sample, share, state.seed)?;
+                    (train, Some(validation))
+                }
+                None => (sample, None),
+            };
+            progress!(
+                self.options,
+                "Optimizing with {} bytes of data.",
+                sampler.total_size()
+            );
+
+            // Every stage starts with fresh removal counts and validation.
+            if state.sample_size != Some(size) {
+                state.sample_size = Some(size);
+                state.removal_count.clear();
+                state.best_validation = None;
+                if let Some(tokenset) = tokenset.as_ref() {
+                    save_checkpoint(&self.checkpoint_dir(), tokenset, &state);
+                }
+            }
+
+            if let Some(tokenset) = tokenset.as_ref() {
+                let stats = self.get_stats(&sampler, tokenset);
+                progress!(
+                    self.options,
+                    "bytes / token (bigger data): {}",
+                    stats.bytes_per_token()
+                );
+            }
+
+            let stats =
+                self.optimize_with_state(&sampler, validation.as_ref(), tokenset, &mut state);
+            progress!(
+                self.options,
+                "bytes / token (optimized): {}",
+                stats.bytes_per_token()
+            );
+
+            let mut new_full_stats = self.get_stats(&full_sampler, &stats.token_set);
+            new_full_stats.stop_reason = stats.stop_reason;
+            progress!(
+                self.options,
+                "bytes / token (full data): {}",
+                new_full_stats.bytes_per_token()
+            );
+
+            // A stage stopped by a limit ends the run.
+            if last || stats.stop_reason.is_some_and(|reason| reason.is_limit()) {
+                break new_full_stats;
+            }
+
+            if size > 1 << 24
+                && (stats.bytes_per_token() - new_full_stats.bytes_per_token()).abs()
+                    / new_full_stats.bytes_per_token()
+                    < 0.005
+            {
+                progress!(self.options, "The difference is less than 0.5%. Stopping.");
+                break new_full_stats;
+            }
+
+            tokenset = Some(stats.token_set);
+
+            size *= 2;
+        };
+
+        Ok(full_stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_token_bpe_most_frequent_pair() {
+        let mut token_set = TokenSet::new_bytes(Processing::Raw);
+        let nn = token_set.add_token(b"\n\n") as u32;
+        let mut stats = TokenStats::new(token_set, None);
+
+        let (a, b, c, x) = (b'a' as u32, b'b' as u32, b'c' as u32, b'x' as u32);
+        // "\n\nx" is not a valid token.
+        stats.pair_counts.insert((nn, x), 11);
+        stats.pair_counts.insert((c, a), 9);
+        stats.pair_counts.insert((b, c), 9);
+        stats.pair_counts.insert((a, b), 8);
+
+        let (token_set, count) = add_token_bpe(&stats).unwrap();
+        assert_eq!(token_set.tokens.last(), Some(&Token::Str(b"bc".to_vec())));
+        assert_eq!(count, 9);
+
+        stats.pair_counts.remove(&(b, c));
+        let (token_set, count) = add_token_bpe(&stats).unwrap();
+        assert_eq!(token_set.tokens.last(), Some(&Token::Str(b"ca".to_vec())));
+        assert_eq!(count, 9);
+    }
+
+    #[test]
+    fn evaluate_removals_in_order() {
+        let text = "the cat sat on the mat\n\nthe end\n\n".repeat(20);
+        let sampler = MemorySampler::from_str(&text, 64);
+        let cache = TokenizerCache::new(&sampler, None);
+
+        let mut token_set = TokenSet::new_bytes(Processing::Raw);
+        for token in ["the", "at", "the ", "en"] {
+            token_set.add_token(token.as_bytes());
+        }
+        let candidates = ["the", "at", "the ", "en"]
+            .iter()
+            .map(|s| s.as_bytes().to_vec())
+            .collect::<Vec<_>>();
+
+        let parallel = evaluate_removals(&token_set, &candidates, &NoopBytesOptimizer {}, &cache);
+        assert_eq!(parallel.len(), candidates.len());
+
+        for (removed, (stats, newer_stats)) in candidates.iter().zip(parallel) {
+            let (expected, expected_newer) =
+                evaluate_removal(&token_set, removed, &NoopBytesOptimizer {}, &cache);
+            assert!(stats.token_set.find_token(removed).is_none());
+            assert_eq!(stats.total_tokens, expected.total_tokens);
+            assert_eq!(stats.pair_counts, expected.pair_counts);
+            assert_eq!(
+                newer_stats.map(|s| s.total_tokens),
+                expected_newer.map(|s| s.total_tokens)
+            );
+        }
+    }
+
+    #[test]
+    fn removal_cost_estimates() {
+        let mut token_set = TokenSet::new_bytes(Processing::Raw);
+        token_set.add_token(b"ab");
+        token_set.add_token(b"abc");
+        token_set.add_token(b"xyz");
+        let sampler = MemorySampler::from_str("abc abc ab", 1 << 10);
+        let stats = tokenize_file(&token_set, &sampler, None);
+
+        let mut estimates = estimate_removal_costs(&stats);
+        estimates.sort();
+        assert_eq!(
+            estimates,
+            vec![
+                (b"ab".to_vec(), 1, 1),
+                (b"abc".to_vec(), 2, 2),
+                (b"xyz".to_vec(), 0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn trajectory_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = dir.path().join("trajectory.jsonl");
+        let options = OptimizerOptions {
+            threads: 2,
+            trajectory_log: Some(log_path.clone()),
+            ..OptimizerOptions::default()
+        };
+        let text = "the cat sat on the mat\n\nthe end of the tale\n\n".repeat(10);
+        let sampler = MemorySampler::from_str(&text, 64);
+        let mut state = OptimizerState::default();
+
+        let stats = optimize_tokenset(
+            262,
+            &sampler,
+            Processing::Raw,
+            TokenType::Bytes,
+            None,
+            None,
+            None,
+            dir.path(),
+            &options,
+            &mut state,
+        );
+
+        let log = std::fs::read_to_string(&log_path).unwrap();
+        let steps = log
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(steps.len() as u64, state.iteration);
+        assert_eq!(state.trajectory.len(), steps.len());
+        assert_eq!(steps[0], state.trajectory[0].to_json());
+        assert_eq!(steps[0]["move"], "add");
+        assert_eq!(steps[0]["total_tokens_before"], text.len());
+        for pair in steps.windows(2) {
+            assert_eq!(pair[1]["total_tokens_before"], pair[0]["total_tokens"]);
+            assert_eq!(
+                pair[1]["iteration"],
+                pair[0]["iteration"].as_u64().unwrap() + 1
+            );
+        }
+        assert_eq!(steps.last().unwrap()["total_tokens"], stats.total_tokens);
+        assert!(steps.iter().any(|s| s["move"] == "remove_add"));
+    }
+
+    #[test]
+    fn validation() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = (0..40)
+            .map(|i| {
+                format!(
+                    "the cat {} sat on the mat\n\nthe end of tale {}\n\n",
+                    i % 7,
+                    i
+                )
+            })
+            .collect::<String>();
+        let (train, validation) = MemorySampler::from_str(&text, 64).split(0.3, 0);
+
+        let run = |patience| {
+            let options = OptimizerOptions {
+                threads: 1,
+                patience,
+                ..OptimizerOptions::default()
+            };
+            let mut state = OptimizerState::default();
+            let stats = optimize_tokenset(
+                264,
+                &train,
+                Processing::Raw,
+                TokenType::Bytes,
+                None,
+                None,
+                Some(&validation),
+                dir.path(),
+                &options,
+                &mut state,
+            );
+            (stats, state)
+        };
+
+        let (stats, state) = run(None);
+        let best = state.best_validation.as_ref().unwrap();
+        let mut best_set = best.token_set.clone();
+        best_set.sort();
+        assert_eq!(stats.token_set.tokens, best_set.tokens);
+        let full_size_steps = state
+            .trajectory
+            .iter()
+            .filter(|step| step.ntokens == 264)
+            .collect::<Vec<_>>();
+        assert!(full_size_steps.len() > 1);
+        let best_step = full_size_steps
+            .iter()
+            .find(|step| step.iteration == best.iteration)
+            .unwrap();
+        assert_eq!(
+            best_step.validation_bytes_per_token,
+            Some(best.bytes_per_token)
+        );
+        assert!(full_size_steps
+            .iter()
+            .all(|step| step.validation_bytes_per_token.unwrap() <= best.bytes_per_token));
+
+        // With no patience, the run stops as soon as the target size is reached.
+        let (_, state) = run(Some(0));
+        assert_eq!(state.iteration, full_size_steps[0].iteration);
+
+        let single = MemorySampler::from_str("a single paragraph\n", 64);
+        for seed in 0..4 {
+            assert!(matches!(
+                split_validation(&single, 0.5, seed),
+                Err(OptimizeError::EmptyValidation)
+            ));
+        }
+        for share in [0.0, 1.0] {
+            assert!(matches!(
+                split_validation(&single, share, 0),
+                Err(OptimizeError::ValidationShare(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = "the cat sat on the mat\n\nthe end of the tale\n\n".repeat(10);
+        let sampler = MemorySampler::from_str(&text, 64);
+
+        let run = |options: OptimizerOptions| {
+            let options = OptimizerOptions {
+                threads: 1,
+                ..options
+            };
+            let mut state = OptimizerState::default();
+            let stats = optimize_tokenset(
+                262,
+                &sampler,
+                Processing::Raw,
+                TokenType::Bytes,
+                None,
+                None,
+                None,
+                dir.path(),
+                &options,
+                &mut state,
+            );
+            (stats, state)
+        };
+
+        let (stats, state) = run(OptimizerOptions::default());
+        assert_eq!(stats.stop_reason, Some(StopReason::Converged));
+        assert_eq!(stats.to_json()["stats"]["stop_reason"], "converged");
+        let full_size_steps = state
+            .trajectory
+            .iter()
+            .filter(|step| step.ntokens == 262)
+            .collect::<Vec<_>>();
+        assert!(full_size_steps.len() > 1);
+
+        // The limits don't stop the growing steps.
+        let (stats, state) = run(OptimizerOptions {
+            max_steps: Some(3),
+            ..OptimizerOptions::default()
+        });
+        assert_eq!(stats.stop_reason, Some(StopReason::MaxSteps));
+        assert_eq!(state.iteration, full_size_steps[0].iteration);
+        assert_eq!(stats.total_tokens, full_size_steps[0].total_tokens);
+
+        let (stats, state) = run(OptimizerOptions {
+            max_steps: Some(full_size_steps[1].iteration),
+            ..OptimizerOptions::default()
+        });
+        assert_eq!(stats.stop_reason, Some(StopReason::MaxSteps));
+        assert_eq!(state.iteration, full_size_steps[1].iteration);
+
+        let (stats, state) = run(OptimizerOptions {
+            max_time: Some(Duration::ZERO),
+            ..OptimizerOptions::default()
+        });
+        assert_eq!(stats.stop_reason, Some(StopReason::MaxTime));
+        assert_eq!(state.iteration, full_size_steps[0].iteration);
+        assert_eq!(stats.ntokens(), 262);
+
+        // The growing steps are never stopped, the first one at the target
+        // size always is.
+        let (stats, state) = run(OptimizerOptions {
+            min_relative_gain: Some(1.0),
+            ..OptimizerOptions::default()
+        });
+        assert_eq!(stats.stop_reason, Some(StopReason::MinRelativeGain));
+        assert_eq!(state.iteration, full_size_steps[1].iteration);
+    }
+
+    #[test]
+    fn nested_ladder() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = (0..40)
+            .map(|i| {
+                format!(
+                    "the cat {} sat on the mat\n\nthe end of tale {}\n\n",
+                    i % 7,
+                    i
+                )
+            })
+            .collect::<String>();
+        let sampler = MemorySampler::from_str(&text, 64);
+        let options = OptimizerOptions {
+            threads: 1,
+            ..OptimizerOptions::default()
+        };
+        let optimizer = Optimizer::new(
+            270,
+            Processing::Raw,
+            TokenType::Bytes,
+            None,
+            dir.path(),
+            options,
+        );
+        let mut state = OptimizerState::default();
+
+        let sizes = [258, 264, 270];
+        let stats = optimizer
+            .optimize_ladder(&sizes, true, &sampler, None, None, &mut state)
+            .unwrap();
+        assert_eq!(stats.ntokens(), 270);
+        assert_eq!(stats.stop_reason, Some(StopReason::Converged));
+        assert_eq!(state.ladder_size, Some(270));
+
+        let token_sets = sizes
+            .iter()
+            .map(|size| {
+                let path = dir.path().join(format!("tokens{}_raw_bytes.json", size));
+                crate::tokenset::read_token_set(path.to_str().unwrap()).unwrap()
+            })
+            .collect::<Vec<_>>();
+        for pair in token_sets.windows(2) {
+            assert_eq!(pair[0].tokens[..], pair[1].tokens[..pair[0].ntokens()]);
+        }
+        assert_eq!(token_sets[2].tokens, stats.token_set.tokens);
+        assert_eq!(state.fixed_tokens, token_sets[1].tokens);
+        let retokenized = tokenize_file(&stats.token_set, &sampler, None);
+        assert_eq!(stats.token_counts, retokenized.token_counts);
+
+        let optimizer = Optimizer::new(
+            270,
+            Processing::Raw,
+            TokenType::Bits4,
+            None,
+            dir.path(),
+            OptimizerOptions::default(),
+        );
+        let result = optimizer.optimize_ladder(&sizes, true, &sampler, None, None, &mut state);
+        assert!(matches!(
+            result,
+            Err(OptimizeError::NestedTokenType(TokenType::Bits4))
+        ));
+
+        let result = optimizer.optimize_ladder(&[], false, &sampler, None, None, &mut state);
+        assert!(matches!(result, Err(OptimizeError::LadderSizes)));
+        let result =
+            optimizer.optimize_ladder(&[258, 264], false, &sampler, None, None, &mut state);
+        assert!(matches!(result, Err(OptimizeError::LadderResume(270))));
     }
 }
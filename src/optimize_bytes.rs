@@ -1,10 +1,9 @@
 use std::cmp::min;
 use std::mem;
 
+use crate::processing::Processing;
 use crate::stats2::TokenStats;
 use crate::tokenset::{Token, TokenSet, TokenType};
-use crate::processing::Processing;
-
 
 pub trait BytesOptimizer {
     fn optimize_bytes(token_stats: &TokenStats, n_byte_tokens: usize) -> TokenSet;
@@ -80,7 +79,12 @@ pub struct HuffOptimizer {}
 impl BytesOptimizer for HuffOptimizer {
     fn optimize_bytes(token_stats: &TokenStats, n_byte_ext_tokens: usize) -> TokenSet {
         let mut counts = (0..=255).map(|i| (i, 1)).collect::<Vec<(u8, u64)>>();
-        for (token, count) in token_stats.token_set.tokens.iter().zip(token_stats.token_counts.iter()) {
+        for (token, count) in token_stats
+            .token_set
+            .tokens
+            .iter()
+            .zip(token_stats.token_counts.iter())
+        {
             if let Token::Str(s) = token {
                 if s.len() == 1 {
                     counts[s[0] as usize] = (s[0], count + 1);
@@ -88,21 +92,31 @@ impl BytesOptimizer for HuffOptimizer {
             }
         }
 
-        for (seq, count) in token_stats.token_set.sequences.iter().zip(token_stats.seq_counts.iter()) {
+        for (seq, count) in token_stats
+            .token_set
+            .sequences
+            .iter()
+            .zip(token_stats.seq_counts.iter())
+        {
             assert_eq!(seq.string.len(), 1);
             counts[seq.string[0] as usize] = (seq.string[0], count + 1);
         }
 
         let mut best_token_set = None;
         let mut best_total_tokens = None;
-    
+
         let max_ext_tokens = min(n_byte_ext_tokens - 1, 10);
-    
+
         for n_ext_tokens in 2..=max_ext_tokens {
             let n_byte_tokens = n_byte_ext_tokens - n_ext_tokens;
-    
-            let token_set = optimize_bytes_tokenset(&counts, n_byte_tokens, n_ext_tokens, token_stats.token_set.processing);
-    
+
+            let token_set = optimize_bytes_tokenset(
+                &counts,
+                n_byte_tokens,
+                n_ext_tokens,
+                token_stats.token_set.processing,
+            );
+
             let mut total = 0;
 
             for token in token_set.tokens.iter() {
@@ -116,13 +130,13 @@ impl BytesOptimizer for HuffOptimizer {
                 assert_eq!(seq.string.len(), 1);
                 total += counts[seq.string[0] as usize].1 * seq.tokens.len() as u64;
             }
-    
+
             if best_total_tokens.is_none() || total < best_total_tokens.unwrap() {
                 best_token_set = Some(token_set);
                 best_total_tokens = Some(total);
             }
         }
-    
+
         let mut best_token_set = best_token_set.unwrap();
 
         // Adding multi-byte tokens from the input tokenset into the new
@@ -139,7 +153,6 @@ impl BytesOptimizer for HuffOptimizer {
     }
 }
 
-
 #[derive(Debug)]
 struct CharsSplit {
     /// First character in the range
@@ -186,7 +199,6 @@ fn node_to_split(node: &HuffmanNode) -> CharsSplit {
     }
 }
 
-
 /// Finds an optimal split of an interval of characters into a given number of
 /// parts.
 fn optimize_splits(counts: &[(u8, u64)], parts: usize) -> Vec<CharsSplit> {
@@ -273,7 +285,7 @@ fn optimize_ext_encoding(counts: &[(u8, u64)], n_ext_tokens: usize) -> Vec<(u8,
     encodings
 }
 
-fn optimize_bytes_tokenset(
+pub fn optimize_bytes_tokenset(
     counts: &[(u8, u64)],
     n_char_tokens: usize,
     n_ext_tokens: usize,
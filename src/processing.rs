@@ -1,7 +1,9 @@
 use clap::ValueEnum;
 use serde::Serialize;
 use std::fmt;
+use std::fs::File;
 use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use tempfile::NamedTempFile;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ValueEnum)]
 #[serde(rename_all = "lowercase")]
@@ -23,6 +25,26 @@ impl fmt::Display for Processing {
     }
 }
 
+/// Version of the scheme used to escape marker characters that are already
+/// present in the raw text:
+///
+/// 0. No escaping. A text containing `\x14`, `\x15` or `\x16` can't be restored.
+/// 1. `\x10` is inserted before each literal `\x10`, `\x14`, `\x15` and `\x16`.
+pub const ESCAPING_VERSION: u32 = 1;
+
+const ESCAPE: char = '\x10';
+
+fn needs_escaping(ch: char) -> bool {
+    matches!(ch, '\x10' | '\x14' | '\x15' | '\x16')
+}
+
+fn push_escaped(out: &mut String, ch: char, escaping: u32) {
+    if escaping >= 1 && needs_escaping(ch) {
+        out.push(ESCAPE);
+    }
+    out.push(ch);
+}
+
 enum CharType {
     Letter,
     NonLetter,
@@ -39,6 +61,23 @@ fn get_char_type(ch: char) -> CharType {
     }
 }
 
+/// Returns the lowercase version of `ch` if it is a single character which
+/// turns back into `ch` when uppercased.
+fn reversible_lowercase(ch: char) -> Option<char> {
+    let mut lower = ch.to_lowercase();
+    let lo = lower.next()?;
+    if lower.next().is_some() {
+        return None;
+    }
+
+    let mut upper = lo.to_uppercase();
+    if upper.next() == Some(ch) && upper.next().is_none() {
+        Some(lo)
+    } else {
+        None
+    }
+}
+
 fn add_word(out: &mut String, word: &str) {
     assert!(!word.is_empty());
 
@@ -47,11 +86,16 @@ fn add_word(out: &mut String, word: &str) {
     let rest = chars.as_str();
 
     if first.is_uppercase() {
-        if rest.chars().all(|ch| ch.is_lowercase()) {
+        if let (Some(lo), true) = (
+            reversible_lowercase(first),
+            rest.chars().all(|ch| ch.is_lowercase()),
+        ) {
             out.push('\x14');
-            out.push(first.to_lowercase().next().unwrap());
+            out.push(lo);
             out.push_str(rest);
-        } else if rest.chars().all(|ch| ch.is_uppercase()) {
+        } else if rest.chars().all(|ch| ch.is_uppercase())
+            && word.to_lowercase().to_uppercase() == word
+        {
             out.push('\x15');
             out.push_str(word.to_lowercase().as_str());
         } else {
@@ -76,7 +120,41 @@ enum State {
 /// 2. Removes a single space between words. In the sequence <letter> `\x16` <space> <letter>, the space is removed.
 /// 3. A capitalized word (a word starting with a capital letter, with remaining letters lowercase) is replaced by a `\x14` character followed by the lowercase version of the word.
 /// 4. An all-uppercase word is replaced by a `\x15` character followed by the lowercase version of the word.
+/// 5. Literal `\x10`, `\x14`, `\x15` and `\x16` characters are escaped by `\x10` (see `ESCAPING_VERSION`).
 pub fn process(text: &str) -> String {
+    process_escaped(text, ESCAPING_VERSION)
+}
+
+/// Same as `process`, with the given version of escaping, so that the text
+/// matches token sets made with an older version. Without escaping, fails if
+/// the text contains marker characters, since the result couldn't be
+/// unprocessed.
+pub fn process_with_escaping(text: &str, escaping: u32) -> io::Result<String> {
+    if escaping == 0 {
+        if let Some(pos) = find_marker(text) {
+            return Err(marker_error(format!("byte {}", pos)));
+        }
+    }
+    Ok(process_escaped(text, escaping))
+}
+
+/// Returns the byte position of the first marker character in the text.
+fn find_marker(text: &str) -> Option<usize> {
+    text.find(['\x14', '\x15', '\x16'])
+}
+
+fn marker_error(position: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "{}: the text contains a CapsWords marker character, which can't be \
+             processed without escaping",
+            position
+        ),
+    )
+}
+
+fn process_escaped(text: &str, escaping: u32) -> String {
     let mut out = String::with_capacity(2 * text.len());
     let mut state = State::NonWord;
     let mut word = String::new();
@@ -88,7 +166,7 @@ pub fn process(text: &str) -> String {
                 State::Word
             }
             (State::NonWord, CharType::Space | CharType::NonLetter) => {
-                out.push(ch);
+                push_escaped(&mut out, ch, escaping);
                 State::NonWord
             }
             (State::Word, CharType::Letter) => {
@@ -103,7 +181,7 @@ pub fn process(text: &str) -> String {
             (State::Word, CharType::NonLetter) => {
                 add_word(&mut out, &word);
                 word.clear();
-                out.push(ch);
+                push_escaped(&mut out, ch, escaping);
                 State::NonWord
             }
             (State::SpaceAfterWord, CharType::Letter) => {
@@ -113,7 +191,7 @@ pub fn process(text: &str) -> String {
             }
             (State::SpaceAfterWord, CharType::Space | CharType::NonLetter) => {
                 out.push(' ');
-                out.push(ch);
+                push_escaped(&mut out, ch, escaping);
                 State::NonWord
             }
         };
@@ -128,22 +206,136 @@ pub fn process(text: &str) -> String {
     out
 }
 
+/// Restores the text transformed by `process`:
+///
+/// 1. `\x16` between two letters is replaced by a space, otherwise it is dropped.
+/// 2. The letter following `\x14` is capitalized.
+/// 3. All the letters between `\x15` and the following `\x16` are capitalized.
+/// 4. A character following `\x10` is copied literally.
+pub fn unprocess(text: &str) -> String {
+    unprocess_with_escaping(text, ESCAPING_VERSION)
+}
+
+/// Same as `unprocess`, for a text processed with the given version of
+/// escaping.
+pub fn unprocess_with_escaping(text: &str, escaping: u32) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    let mut capitalize_next = false;
+    let mut all_caps = false;
+    let mut after_letter = false;
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            ESCAPE if escaping >= 1 => {
+                if let Some(escaped) = chars.next() {
+                    out.push(escaped);
+                }
+                after_letter = false;
+                continue;
+            }
+            '\x14' => capitalize_next = true,
+            '\x15' => all_caps = true,
+            '\x16' => {
+                all_caps = false;
+                let before_letter = match chars.peek() {
+                    Some(&next) => next.is_alphabetic() || next == '\x14' || next == '\x15',
+                    None => false,
+                };
+                if after_letter && before_letter {
+                    out.push(' ');
+                }
+            }
+            _ => {
+                if capitalize_next || all_caps {
+                    out.extend(ch.to_uppercase());
+                    capitalize_next = false;
+                } else {
+                    out.push(ch);
+                }
+            }
+        }
+        after_letter = ch.is_alphabetic();
+    }
+
+    out
+}
+
+/// Processes the input line by line, keeping the line ends intact.
 pub fn process_file<R: Read, W: Write>(input: &mut R, output: &mut W) -> io::Result<()> {
-    let reader = BufReader::new(input);
+    process_file_with_escaping(input, output, ESCAPING_VERSION)
+}
+
+/// Same as `process_file`, with the given version of escaping. Without
+/// escaping, fails if the input contains marker characters.
+pub fn process_file_with_escaping<R: Read, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    escaping: u32,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(input);
+    let mut writer = BufWriter::new(output);
+    let mut line = String::new();
+    let mut line_no = 0;
+
+    while reader.read_line(&mut line)? > 0 {
+        if escaping == 0 {
+            if let Some(pos) = find_marker(&line) {
+                return Err(marker_error(format!("line {}, byte {}", line_no + 1, pos)));
+            }
+        }
+        writer.write_all(process_escaped(&line, escaping).as_bytes())?;
+        line.clear();
+        line_no += 1;
+    }
+
+    writer.flush()
+}
+
+pub fn unprocess_file<R: Read, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    escaping: u32,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(input);
     let mut writer = BufWriter::new(output);
+    let mut line = String::new();
 
-    for line in reader.lines() {
-        let line = line?;
-        let processed = process(&line);
-        writer.write_all(processed.as_bytes())?;
-        writer.write(b"\n")?;
+    while reader.read_line(&mut line)? > 0 {
+        writer.write_all(unprocess_with_escaping(&line, escaping).as_bytes())?;
+        line.clear();
     }
 
-    Ok(())
+    writer.flush()
+}
+
+/// Returns the name of a file with the data processed according to
+/// `processing` and `escaping`. If the data needs processing and
+/// `filename_processed` isn't given, the data is processed into a temporary
+/// file, which is deleted when the returned `NamedTempFile` is dropped.
+pub fn maybe_process_file(
+    filename_raw: &str,
+    filename_processed: Option<&str>,
+    processing: Processing,
+    escaping: u32,
+) -> io::Result<(String, Option<NamedTempFile>)> {
+    match (filename_processed, processing) {
+        (_, Processing::Raw) => Ok((filename_raw.to_string(), None)),
+        (Some(f), Processing::CapsWords) => Ok((f.to_string(), None)),
+        (None, Processing::CapsWords) => {
+            let mut temp_processed = NamedTempFile::new()?;
+            let mut input = File::open(filename_raw)?;
+            process_file_with_escaping(&mut input, &mut temp_processed, escaping)?;
+            let filename = temp_processed.path().to_str().unwrap().to_string();
+            Ok((filename, Some(temp_processed)))
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+
     #[test]
     fn test_process() {
         assert_eq!(super::process("Hello, world!"), "\x14hello\x16, world\x16!");
@@ -164,4 +356,85 @@ mod tests {
         assert_eq!(super::process("Hello World"), "\x14hello\x16\x14world\x16");
         assert_eq!(super::process("Hello WORLD"), "\x14hello\x16\x15world\x16");
     }
+
+    #[test]
+    fn test_unprocess() {
+        for text in [
+            "Hello, world!",
+            "hello, world!",
+            "HELLO, world!",
+            "HeLLo, world!",
+            "Hello world!",
+            "Hello , world!",
+            "Hello, world ",
+            "Hello  World",
+            "Hello WORLD",
+            "A I ΣΑΣ Straße",
+            "İstanbul ǅemal ϴ",
+        ] {
+            assert_eq!(super::unprocess(&super::process(text)), text);
+        }
+    }
+
+    #[test]
+    fn test_escaping() {
+        assert_eq!(
+            super::process("a\x16b \x10\x14C"),
+            "a\x16\x10\x16b\x16 \x10\x10\x10\x14\x14c\x16"
+        );
+        assert_eq!(
+            super::unprocess("a\x16\x10\x16b\x16 \x10\x10\x10\x14\x14c\x16"),
+            "a\x16b \x10\x14C"
+        );
+        assert_eq!(
+            super::unprocess_with_escaping("\x14a\x16\x10b\x16", 0),
+            "A\x10b"
+        );
+        assert_eq!(
+            super::process_with_escaping("\x10Ab", 0).unwrap(),
+            "\x10\x14ab\x16"
+        );
+        assert_eq!(super::find_marker("Hello,\x16world!"), Some(6));
+        let error = super::process_with_escaping("a\x15b", 0).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(
+            super::process_with_escaping("a\x15b", 1).unwrap(),
+            "a\x16\x10\x15b\x16"
+        );
+
+        let mut output = Vec::new();
+        let input = "Hello\nworld\x14\n";
+        let error =
+            super::process_file_with_escaping(&mut input.as_bytes(), &mut output, 0).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(error.to_string().starts_with("line 2, byte 5:"));
+    }
+
+    #[test]
+    fn test_process_file() {
+        let mut output = Vec::new();
+        super::process_file(&mut "Hello world\r\nBye".as_bytes(), &mut output).unwrap();
+        assert_eq!(output, "\x14hello\x16world\x16\r\n\x14bye\x16".as_bytes());
+
+        let mut restored = Vec::new();
+        super::unprocess_file(
+            &mut output.as_slice(),
+            &mut restored,
+            super::ESCAPING_VERSION,
+        )
+        .unwrap();
+        assert_eq!(restored, "Hello world\r\nBye".as_bytes());
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(text in "\\PC*") {
+            prop_assert_eq!(super::unprocess(&super::process(&text)), text);
+        }
+
+        #[test]
+        fn round_trip_words(text in "[a-zA-Zà-öÀ-ÖσςΣİıǅϴß ,.\n\x10\x14\x15\x16]*") {
+            prop_assert_eq!(super::unprocess(&super::process(&text)), text);
+        }
+    }
 }
@@ -1,6 +1,11 @@
+use rustc_hash::FxHashMap;
 use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
 
-use super::tokenset::TokenSet;
+use super::checkpoint::StopReason;
+use super::tokenset::{Token, TokenSet};
 
 #[derive(Clone, Debug)]
 pub struct TokenStats {
@@ -10,9 +15,12 @@ pub struct TokenStats {
     pub scanned_bytes: u64,
     pub token_counts: Vec<u64>,
     pub seq_counts: Vec<u64>,
-    /// Counts for pairs of tokens (token1, token2). Indexed by
-    /// token1_id * ntokens + token2_id
-    pub pair_counts: Vec<u64>,
+    /// Counts for pairs of adjacent `Str` tokens (token1_id, token2_id). Only
+    /// the pairs that occur are stored, so that large token sets fit in
+    /// memory.
+    pub pair_counts: FxHashMap<(u32, u32), u64>,
+    /// Set on the result of an optimization run.
+    pub stop_reason: Option<StopReason>,
 }
 
 impl TokenStats {
@@ -26,7 +34,8 @@ impl TokenStats {
             scanned_bytes: 0,
             token_counts: vec![0; ntokens],
             seq_counts: vec![0; nseqs],
-            pair_counts: vec![0; ntokens*ntokens],
+            pair_counts: FxHashMap::default(),
+            stop_reason: None,
         }
     }
 
@@ -38,7 +47,8 @@ impl TokenStats {
             scanned_bytes: self.scanned_bytes,
             token_counts: self.token_counts.clone(),
             seq_counts: self.seq_counts.clone(),
-            pair_counts: Vec::new(),
+            pair_counts: FxHashMap::default(),
+            stop_reason: self.stop_reason,
         }
     }
 
@@ -50,6 +60,27 @@ impl TokenStats {
         self.scanned_bytes as f64 / self.total_tokens as f64
     }
 
+    /// The number of scanned bytes encoded by sequences with ext tokens, i.e.
+    /// bytes that fall back to ext tokens.
+    pub fn ext_sequence_bytes(&self) -> u64 {
+        self.token_set
+            .sequences
+            .iter()
+            .zip(self.seq_counts.iter())
+            .filter(|(seq, _)| {
+                seq.tokens
+                    .iter()
+                    .any(|&t| matches!(self.token_set.tokens[t], Token::Ext(_)))
+            })
+            .map(|(seq, &count)| seq.string.len() as u64 * count)
+            .sum()
+    }
+
+    /// The number of tokens that weren't used at all.
+    pub fn unused_tokens(&self) -> usize {
+        self.token_counts.iter().filter(|&&c| c == 0).count()
+    }
+
     pub fn to_json(&self) -> Value {
         let mut result = self.token_set.to_json();
 
@@ -62,6 +93,9 @@ impl TokenStats {
             stats["initial_size"] = s.into();
             stats["bytes_per_token"] = (s as f64 / self.total_tokens as f64).into();
         }
+        if let Some(reason) = self.stop_reason {
+            stats["stop_reason"] = reason.as_str().into();
+        }
 
         result["stats"] = stats;
 
@@ -77,8 +111,109 @@ impl TokenStats {
         for i in 0..self.seq_counts.len() {
             self.seq_counts[i] += other.seq_counts[i];
         }
-        for i in 0..self.pair_counts.len() {
-            self.pair_counts[i] += other.pair_counts[i];
+        for (&pair, &count) in other.pair_counts.iter() {
+            *self.pair_counts.entry(pair).or_insert(0) += count;
         }
     }
+
+    /// Reorders the token set with `TokenSet::put_first` and moves the counts
+    /// to the new token IDs, without tokenizing again.
+    pub fn put_first(&mut self, prefix: &[Token]) {
+        let old_ids = self
+            .token_set
+            .tokens
+            .iter()
+            .cloned()
+            .zip(0..)
+            .collect::<HashMap<_, usize>>();
+        let old_seqs = self
+            .token_set
+            .sequences
+            .iter()
+            .map(|seq| seq.string.clone())
+            .zip(0..)
+            .collect::<HashMap<_, usize>>();
+        self.token_set.put_first(prefix);
+
+        let mut new_ids = vec![0; old_ids.len()];
+        for (new_id, token) in self.token_set.tokens.iter().enumerate() {
+            new_ids[old_ids[token]] = new_id;
+        }
+        self.token_counts = self
+            .token_set
+            .tokens
+            .iter()
+            .map(|token| self.token_counts[old_ids[token]])
+            .collect();
+        self.seq_counts = self
+            .token_set
+            .sequences
+            .iter()
+            .map(|seq| self.seq_counts[old_seqs[&seq.string]])
+            .collect();
+        self.pair_counts = self
+            .pair_counts
+            .iter()
+            .map(|(&(first, second), &count)| {
+                let pair = (
+                    new_ids[first as usize] as u32,
+                    new_ids[second as usize] as u32,
+                );
+                (pair, count)
+            })
+            .collect();
+    }
+
+    /// Writes the token set together with the stats to
+    /// `<tokens_dir>/<token set name>.json` and returns the path.
+    pub fn save(&self, tokens_dir: &Path) -> io::Result<PathBuf> {
+        let output_path = tokens_dir.join(format!("{}.json", self.token_set.name()));
+        let serialized = serde_json::to_string(&self.to_json()).unwrap();
+        std::fs::write(&output_path, serialized)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", output_path.display(), e)))?;
+        Ok(output_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch_tokenize::tokenize_file;
+    use crate::input::memory_sampler::MemorySampler;
+    use crate::processing::Processing;
+
+    #[test]
+    fn usage() {
+        let mut token_set = TokenSet::new_bits4(Processing::Raw, true);
+        token_set.add_token(b"a");
+        token_set.add_token(b"b");
+        token_set.add_token(b"c");
+
+        let sampler = MemorySampler::from_str("abcabé", 1 << 10);
+        let stats = tokenize_file(&token_set, &sampler, None);
+
+        // "é" is encoded by two sequences of ext tokens.
+        assert_eq!(stats.ext_sequence_bytes(), 2);
+        // Ext tokens 12, 3 and 10, 9 encode 0xc3 and 0xa9.
+        assert_eq!(stats.unused_tokens(), 16 - 4);
+    }
+
+    #[test]
+    fn put_first() {
+        let mut token_set = TokenSet::new_bits4(Processing::Raw, true);
+        token_set.add_token(b"a");
+        token_set.add_token(b"b");
+        token_set.add_token(b"ab");
+        token_set.sort();
+
+        let sampler = MemorySampler::from_str("abcabé b", 1 << 10);
+        let mut stats = tokenize_file(&token_set, &sampler, None);
+        stats.put_first(&[Token::Str(b"b".to_vec()), Token::Ext(3)]);
+        assert_eq!(stats.token_set.tokens[0], Token::Str(b"b".to_vec()));
+
+        let expected = tokenize_file(&stats.token_set, &sampler, None);
+        assert_eq!(stats.token_counts, expected.token_counts);
+        assert_eq!(stats.seq_counts, expected.seq_counts);
+        assert_eq!(stats.pair_counts, expected.pair_counts);
+    }
 }
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::VecDeque;
 
 use super::stats2::TokenStats;
 use super::tokenset::{Token, TokenSet};
@@ -23,21 +23,143 @@ struct Span {
     cost: u64,
 }
 
+/// A state of the Aho–Corasick automaton over the strings of all spans. It
+/// corresponds to a prefix of some span string.
 #[derive(Debug)]
 struct SuffixState {
-    suffix: Vec<u8>,
-    span_idx: usize,
-    next: [usize; 256],
+    // The state of the longest proper suffix of this state's string.
+    fail: u32,
+    // Index of the longest span that is a suffix of this state's string.
+    span_idx: u32,
+    // The range of this state's transitions in `edge_bytes` and `edge_targets`.
+    first_edge: u32,
+    nedges: u16,
 }
 
-impl SuffixState {
-    fn new(suffix: Vec<u8>, span_idx: usize) -> Self {
-        SuffixState {
-            suffix,
-            span_idx,
-            next: [0; 256],
+/// The automaton recognizing all spans ending at the current position. Only
+/// the trie edges are stored, sorted by byte; missing transitions follow the
+/// failure links. The root keeps a full table, so that following the links
+/// always ends after a single lookup there.
+#[derive(Debug)]
+struct Automaton {
+    states: Vec<SuffixState>,
+    edge_bytes: Vec<u8>,
+    edge_targets: Vec<u32>,
+    root_next: [u32; 256],
+}
+
+impl Automaton {
+    /// Builds the automaton and fills `suffix_span` of the spans. Takes time
+    /// linear in the total length of the span strings.
+    fn new(spans: &mut [Span]) -> Self {
+        // The trie of the span strings, with sorted children.
+        let mut children: Vec<Vec<(u8, u32)>> = vec![Vec::new()];
+        let mut node_span = vec![0u32];
+        let mut span_node = vec![0u32; spans.len()];
+
+        for (span_idx, span) in spans.iter().enumerate().skip(1) {
+            let mut node = 0;
+            for &byte in span.string.iter() {
+                let edges = &mut children[node];
+                node = match edges.binary_search_by_key(&byte, |&(b, _)| b) {
+                    Ok(i) => edges[i].1 as usize,
+                    Err(i) => {
+                        let child = node_span.len();
+                        edges.insert(i, (byte, child as u32));
+                        children.push(Vec::new());
+                        node_span.push(0);
+                        child
+                    }
+                };
+            }
+            assert!(
+                node_span[node] == 0,
+                "duplicate span {:?}",
+                String::from_utf8_lossy(&span.string)
+            );
+            node_span[node] = span_idx as u32;
+            span_node[span_idx] = node as u32;
+        }
+
+        let mut automaton = Automaton {
+            states: Vec::with_capacity(children.len()),
+            edge_bytes: Vec::with_capacity(children.len()),
+            edge_targets: Vec::with_capacity(children.len()),
+            root_next: [0; 256],
+        };
+
+        for edges in children.iter() {
+            automaton.states.push(SuffixState {
+                fail: 0,
+                span_idx: 0,
+                first_edge: automaton.edge_bytes.len() as u32,
+                nedges: edges.len() as u16,
+            });
+            for &(byte, child) in edges.iter() {
+                automaton.edge_bytes.push(byte);
+                automaton.edge_targets.push(child);
+            }
+        }
+        for &(byte, child) in children[0].iter() {
+            automaton.root_next[byte as usize] = child;
+        }
+
+        // Breadth-first traversal, so that the failure state of every state
+        // is already complete when it is reached.
+        let mut queue = VecDeque::new();
+        queue.push_back(0);
+
+        while let Some(node) = queue.pop_front() {
+            for &(byte, child) in children[node].iter() {
+                let fail = if node == 0 {
+                    0
+                } else {
+                    automaton.next(automaton.states[node].fail, byte)
+                };
+                let span_idx = match node_span[child as usize] {
+                    0 => automaton.states[fail as usize].span_idx,
+                    span_idx => span_idx,
+                };
+
+                let state = &mut automaton.states[child as usize];
+                state.fail = fail;
+                state.span_idx = span_idx;
+                queue.push_back(child as usize);
+            }
+        }
+
+        for (span, &node) in spans.iter_mut().zip(span_node.iter()).skip(1) {
+            let fail = automaton.states[node as usize].fail;
+            span.suffix_span = automaton.states[fail as usize].span_idx as usize;
+        }
+
+        automaton
+    }
+
+    /// The state of the longest suffix of `state`'s string followed by `byte`.
+    #[inline]
+    fn next(&self, mut state: u32, byte: u8) -> u32 {
+        loop {
+            if state == 0 {
+                return self.root_next[byte as usize];
+            }
+            let s = &self.states[state as usize];
+            let first = s.first_edge as usize;
+            let last = first + s.nedges as usize;
+            if let Some(i) = self.edge_bytes[first..last].iter().position(|&b| b == byte) {
+                return self.edge_targets[first + i];
+            }
+            state = s.fail;
         }
     }
+
+    /// The approximate number of bytes taken by the automaton.
+    fn size(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.states.capacity() * std::mem::size_of::<SuffixState>()
+            + self.edge_bytes.capacity()
+            + self.edge_targets.capacity() * std::mem::size_of::<u32>()
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -50,42 +172,43 @@ pub struct CostState {
 pub struct FragmentTokenizer {
     pub token_set: TokenSet,
     spans: Vec<Span>,
-    suffix_states: Vec<SuffixState>,
+    automaton: Automaton,
 }
 
 impl FragmentTokenizer {
     pub fn new(token_set: TokenSet) -> Self {
-        let (spans, span_by_str) = Self::create_spans(&token_set);
-        let suffix_states = Self::create_suffix_states(&spans, &span_by_str);
+        let mut spans = Self::create_spans(&token_set);
+        let automaton = Automaton::new(&mut spans);
         FragmentTokenizer {
             token_set,
             spans,
-            suffix_states,
+            automaton,
         }
     }
 
-    fn create_spans(token_set: &TokenSet) -> (Vec<Span>, HashMap<Vec<u8>, usize>) {
-        let mut spans = Vec::new();
-        let mut span_by_str: HashMap<Vec<u8>, usize> = HashMap::new();
+    /// The number of states of the automaton matching the spans.
+    pub fn nstates(&self) -> usize {
+        self.automaton.states.len()
+    }
+
+    /// The approximate number of bytes taken by the automaton.
+    pub fn automaton_size(&self) -> usize {
+        self.automaton.size()
+    }
 
-        span_by_str.insert(Vec::new(), 0);
-        spans.push(Span {
+    /// Creates the spans of all tokens and sequences. Their `suffix_span` is
+    /// filled in by `Automaton::new`.
+    fn create_spans(token_set: &TokenSet) -> Vec<Span> {
+        let mut spans = vec![Span {
             content: SpanContent::None,
             string: Vec::new(),
             suffix_span: 0,
             cost: 0,
-        });
+        }];
 
         // Populate tokens
         for (idx, token) in token_set.tokens.iter().enumerate() {
             if let Token::Str(string) = token {
-                if span_by_str.insert(string.clone(), spans.len()).is_some() {
-                    dbg!(&token_set.tokens);
-                    dbg!(string);
-                    dbg!(spans);
-                    dbg!(span_by_str);
-                    panic!();
-                }
                 spans.push(Span {
                     content: SpanContent::Token(idx),
                     string: string.clone(),
@@ -97,9 +220,6 @@ impl FragmentTokenizer {
 
         // Populate sequences
         for (idx, seq) in token_set.sequences.iter().enumerate() {
-            assert!(span_by_str
-                .insert(seq.string.clone(), spans.len())
-                .is_none());
             spans.push(Span {
                 content: SpanContent::Sequence(idx),
                 string: seq.string.clone(),
@@ -108,96 +228,22 @@ impl FragmentTokenizer {
             })
         }
 
-        // Populate suffix spans
-        for span in spans[1..].iter_mut() {
-            for start in 1..=(span.string.len() - 1) {
-                let suffix = &span.string[start..];
-                if let Some(&idx) = span_by_str.get(suffix) {
-                    span.suffix_span = idx;
-                    break;
-                }
-            }
-        }
-
-        (spans, span_by_str)
+        spans
     }
 
-    fn create_suffix_states(
-        spans: &[Span],
-        span_by_str: &HashMap<Vec<u8>, usize>,
-    ) -> Vec<SuffixState> {
-        let mut suffix_states = Vec::new();
-        let mut state_by_str: HashMap<Vec<u8>, usize> = HashMap::new();
-
-        suffix_states.push(SuffixState::new(Vec::new(), 0));
-
-        state_by_str.insert(Vec::new(), 0);
-
-        for span in spans.iter() {
-            for end in 1..=span.string.len() {
-                // The suffix is a token prefix
-                let span_prefix = span.string[..end].to_vec();
-
-                if state_by_str.contains_key(&span_prefix) {
-                    continue;
-                }
-
-                let mut suffix_span = 0;
-
-                for start in 0..span_prefix.len() {
-                    if let Some(&idx) = span_by_str.get(&span_prefix[start..]) {
-                        suffix_span = idx;
-                        break;
-                    }
-                }
-
-                assert!(suffix_span > 0);
-
-                let suffix_state = SuffixState::new(span_prefix, suffix_span);
-
-                state_by_str.insert(suffix_state.suffix.clone(), suffix_states.len());
-                suffix_states.push(suffix_state);
-            }
-        }
-
-        for state in suffix_states.iter_mut() {
-            let mut suffix = state.suffix.to_vec();
-
-            for last_byte in 0..=255 {
-                suffix.push(last_byte);
-
-                let mut suffix_id = 0;
-
-                for start in 0..suffix.len() {
-                    let suffix_suffix = &suffix[start..];
-
-                    if let Some(&id) = state_by_str.get(suffix_suffix) {
-                        suffix_id = id;
-                        break;
-                    }
-                }
-
-                assert!(suffix_id > 0);
-                state.next[last_byte as usize] = suffix_id;
-
-                suffix.pop();
-            }
-        }
-
-        suffix_states
-    }
-
-    pub fn process_slice(&self, bytes: &[u8], stats: &mut TokenStats, cost_state: &mut Vec<CostState>) {
-        // let mut cost_state = vec![CostState { cost: 0, span: 0 }];
+    /// Runs the optimal-cost dynamic programming over `bytes`. After the call
+    /// `cost_state[i]` contains the minimal number of tokens needed to encode
+    /// the first `i` bytes and the last span of that encoding.
+    fn compute_costs(&self, bytes: &[u8], cost_state: &mut Vec<CostState>) {
         cost_state.clear();
         cost_state.push(CostState { cost: 0, span: 0 });
-        let mut state = &self.suffix_states[0];
+        let mut state = 0;
 
         for &byte in bytes.iter() {
-            state = &self.suffix_states[state.next[byte as usize]];
+            state = self.automaton.next(state, byte);
 
             let mut best_cost_state: Option<CostState> = None;
-            let mut span_idx = state.span_idx;
+            let mut span_idx = self.automaton.states[state as usize].span_idx as usize;
 
             while span_idx != 0 {
                 let span = &self.spans[span_idx];
@@ -215,41 +261,131 @@ impl FragmentTokenizer {
 
             cost_state.push(best_cost_state.unwrap());
         }
+    }
+
+    /// Returns the minimal number of tokens needed to encode `bytes` with at
+    /// least two spans, i.e. without the token or sequence having exactly this
+    /// string. `bytes` has to be at least two bytes long.
+    pub fn split_cost(&self, bytes: &[u8], cost_state: &mut Vec<CostState>) -> u64 {
+        self.compute_costs(&bytes[..bytes.len() - 1], cost_state);
+
+        let state = bytes
+            .iter()
+            .fold(0, |state, &byte| self.automaton.next(state, byte));
+        let mut span_idx = self.automaton.states[state as usize].span_idx as usize;
+        let mut best_cost = u64::MAX;
+
+        while span_idx != 0 {
+            let span = &self.spans[span_idx];
+            if span.string.len() < bytes.len() {
+                let prev_cost = cost_state[bytes.len() - span.string.len()].cost;
+                best_cost = best_cost.min(prev_cost + span.cost);
+            }
+            span_idx = span.suffix_span;
+        }
+
+        best_cost
+    }
+
+    /// Walks `cost_state` backwards and returns the indices of the spans
+    /// forming the optimal encoding, in the order of their appearance.
+    fn backtrace(&self, cost_state: &[CostState]) -> Vec<usize> {
+        let mut spans = Vec::new();
+        let mut pos = cost_state.len() - 1;
+
+        while pos > 0 {
+            let span_idx = cost_state[pos].span;
+            spans.push(span_idx);
+            pos -= self.spans[span_idx].string.len();
+        }
+
+        spans.reverse();
+        spans
+    }
 
+    pub fn process_slice(
+        &self,
+        bytes: &[u8],
+        stats: &mut TokenStats,
+        cost_state: &mut Vec<CostState>,
+    ) {
+        self.compute_costs(bytes, cost_state);
         self.update_stats(cost_state, bytes, stats);
     }
 
-    fn update_stats(&self, cost_state: &Vec<CostState>, bytes: &[u8], stats: &mut TokenStats) {
+    /// Tokenizes `bytes` and returns the IDs of the tokens in the optimal
+    /// encoding. Sequences are expanded into the tokens they consist of.
+    pub fn encode(&self, bytes: &[u8], cost_state: &mut Vec<CostState>) -> Vec<u32> {
+        self.compute_costs(bytes, cost_state);
+
+        let mut token_ids = Vec::with_capacity(cost_state.last().unwrap().cost as usize);
+
+        for span_idx in self.backtrace(cost_state) {
+            match self.spans[span_idx].content {
+                SpanContent::Token(token_id) => token_ids.push(token_id as u32),
+                SpanContent::Sequence(seq_id) => token_ids.extend(
+                    self.token_set.sequences[seq_id]
+                        .tokens
+                        .iter()
+                        .map(|&token_id| token_id as u32),
+                ),
+                SpanContent::None => unreachable!(),
+            }
+        }
+
+        token_ids
+    }
+
+    /// Returns the indices of the ext tokens that don't appear in the
+    /// encoding of any byte or sequence, and so in the tokenization of any
+    /// text. `Str` tokens are always reachable, since no other span has the
+    /// same string and the string alone is encoded with the token.
+    pub fn unreachable_ext_tokens(&self) -> Vec<usize> {
+        let mut used = vec![false; self.token_set.ntokens()];
+        let mut cost_state = Vec::new();
+
+        let bytes = (0..=255u8).map(|b| vec![b]);
+        let sequences = self
+            .token_set
+            .sequences
+            .iter()
+            .map(|seq| seq.string.clone());
+        for string in bytes.chain(sequences) {
+            for token_id in self.encode(&string, &mut cost_state) {
+                used[token_id as usize] = true;
+            }
+        }
+
+        (0..used.len())
+            .filter(|&i| !used[i] && matches!(self.token_set.tokens[i], Token::Ext(_)))
+            .collect()
+    }
+
+    fn update_stats(&self, cost_state: &[CostState], bytes: &[u8], stats: &mut TokenStats) {
         stats.total_tokens += cost_state.last().unwrap().cost;
         stats.scanned_bytes += bytes.len() as u64;
 
-        let ntokens = stats.token_set.ntokens();
-
         let mut span_counts = vec![0; self.spans.len()];
-        let mut next_token = None;
-        let mut pos = bytes.len();
+        let mut prev_token = None;
 
-        while pos > 0 {
-            let span_idx = cost_state[pos].span;
+        for span_idx in self.backtrace(cost_state) {
             span_counts[span_idx] += 1;
 
-            let span = &self.spans[span_idx];
-            next_token = if let SpanContent::Token(token) = span.content {
-                if let Some(next) = next_token {
-                    let pair_id = token * ntokens + next;
-                    stats.pair_counts[pair_id] += 1;
+            prev_token = if let SpanContent::Token(token) = self.spans[span_idx].content {
+                if let Some(prev) = prev_token {
+                    *stats
+                        .pair_counts
+                        .entry((prev as u32, token as u32))
+                        .or_insert(0) += 1;
                 }
 
                 Some(token)
             } else {
                 None
             };
-
-            pos -= span.string.len();
         }
 
-        for span_idx in 1..self.spans.len() {
-            let count = span_counts[span_idx];
+        for (span_idx, &count) in span_counts.iter().enumerate().skip(1) {
             let span = &self.spans[span_idx];
             match span.content {
                 SpanContent::Sequence(seq_id) => {
@@ -258,10 +394,8 @@ impl FragmentTokenizer {
                     for &token_id in seq.tokens.iter() {
                         stats.token_counts[token_id] += count;
                     }
-                },
-                SpanContent::Token(token_id) => {
-                    stats.token_counts[token_id] += count
-                },
+                }
+                SpanContent::Token(token_id) => stats.token_counts[token_id] += count,
                 SpanContent::None => {
                     dbg!(cost_state);
                     dbg!(span_idx);
@@ -276,6 +410,48 @@ impl FragmentTokenizer {
 mod tests {
     use super::*;
     use crate::processing::Processing;
+    use crate::tokenset::TokenType;
+    use proptest::prelude::*;
+
+    /// The optimal encoding computed by checking every token and sequence at
+    /// every position, preferring the longest last span on ties like
+    /// `FragmentTokenizer`.
+    fn naive_encode(token_set: &TokenSet, bytes: &[u8]) -> Vec<u32> {
+        let mut spans: Vec<(&[u8], Vec<u32>)> = Vec::new();
+        for (id, token) in token_set.tokens.iter().enumerate() {
+            if let Token::Str(s) = token {
+                spans.push((s, vec![id as u32]));
+            }
+        }
+        for seq in token_set.sequences.iter() {
+            let ids = seq.tokens.iter().map(|&t| t as u32).collect();
+            spans.push((&seq.string, ids));
+        }
+        spans.sort_by_key(|(s, _)| std::cmp::Reverse(s.len()));
+
+        let mut best: Vec<Option<(usize, usize)>> = vec![None; bytes.len() + 1];
+        let mut costs = vec![0; bytes.len() + 1];
+        for end in 1..=bytes.len() {
+            for (i, (s, ids)) in spans.iter().enumerate() {
+                if bytes[..end].ends_with(s) {
+                    let cost = costs[end - s.len()] + ids.len();
+                    if best[end].is_none() || cost < costs[end] {
+                        best[end] = Some((i, s.len()));
+                        costs[end] = cost;
+                    }
+                }
+            }
+        }
+
+        let mut parts = Vec::new();
+        let mut end = bytes.len();
+        while end > 0 {
+            let (i, len) = best[end].unwrap();
+            parts.push(spans[i].1.clone());
+            end -= len;
+        }
+        parts.into_iter().rev().flatten().collect()
+    }
 
     #[test]
     fn tokenize() {
@@ -309,4 +485,84 @@ mod tests {
         tokenizer.process_slice("abcde".as_bytes(), &mut stats, &mut buffer);
         assert_eq!(stats.total_tokens, 3);
     }
+
+    #[test]
+    fn unreachable_ext_tokens() {
+        let mut token_set = TokenSet::new(3, Processing::Raw, TokenType::BytesHuff, true);
+        for c in 0..=255 {
+            if c != b'x' {
+                token_set.add_token(&[c]);
+            }
+        }
+        let a = token_set.find_token("a".as_bytes()).unwrap();
+        token_set.add_sequence("x".as_bytes().to_vec(), vec![a, 1]);
+
+        let tokenizer = FragmentTokenizer::new(token_set);
+        assert_eq!(tokenizer.unreachable_ext_tokens(), vec![0, 2]);
+
+        // The bits are only used by the bytes without a token.
+        let tokenizer = FragmentTokenizer::new(TokenSet::new_bits1(Processing::Raw, true));
+        assert!(tokenizer.unreachable_ext_tokens().is_empty());
+    }
+
+    #[test]
+    fn encode() {
+        let mut token_set = TokenSet::new_bits1(Processing::Raw, true);
+        token_set.add_token("a".as_bytes()); // 2
+        token_set.add_token("ab".as_bytes()); // 3
+        token_set.add_token("bc".as_bytes()); // 4
+
+        let tokenizer = FragmentTokenizer::new(token_set);
+        let mut buffer = Vec::new();
+
+        assert_eq!(tokenizer.encode("abc".as_bytes(), &mut buffer), vec![2, 4]);
+        // "d" = 0x64 = 0b01100100 is encoded bit by bit.
+        assert_eq!(
+            tokenizer.encode("abd".as_bytes(), &mut buffer),
+            vec![3, 0, 1, 1, 0, 0, 1, 0, 0]
+        );
+        assert!(tokenizer.encode(&[], &mut buffer).is_empty());
+    }
+
+    #[test]
+    fn split_cost() {
+        let mut token_set = TokenSet::new_bits1(Processing::Raw, true);
+        token_set.add_token("a".as_bytes());
+        token_set.add_token("b".as_bytes());
+        token_set.add_token("ab".as_bytes());
+        token_set.add_token("abab".as_bytes());
+
+        let tokenizer = FragmentTokenizer::new(token_set);
+        let mut buffer = Vec::new();
+
+        assert_eq!(tokenizer.split_cost("ab".as_bytes(), &mut buffer), 2);
+        assert_eq!(tokenizer.split_cost("abab".as_bytes(), &mut buffer), 2);
+        assert_eq!(tokenizer.split_cost("aba".as_bytes(), &mut buffer), 2);
+        // "c" is encoded with 8 ext tokens.
+        assert_eq!(tokenizer.split_cost("ac".as_bytes(), &mut buffer), 9);
+    }
+
+    proptest! {
+        #[test]
+        fn matches_naive(
+            tokens in prop::collection::vec("[abc ]{2,6}", 0..40),
+            text in "[abcd ]{0,60}",
+            bits in any::<bool>(),
+        ) {
+            let mut token_set = if bits {
+                TokenSet::new_bits4(Processing::Raw, true)
+            } else {
+                TokenSet::new_bytes(Processing::Raw)
+            };
+            for token in tokens.iter() {
+                if token_set.find_token(token.as_bytes()).is_none() {
+                    token_set.add_token(token.as_bytes());
+                }
+            }
+
+            let expected = naive_encode(&token_set, text.as_bytes());
+            let tokenizer = FragmentTokenizer::new(token_set);
+            prop_assert_eq!(tokenizer.encode(text.as_bytes(), &mut Vec::new()), expected);
+        }
+    }
 }
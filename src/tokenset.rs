@@ -2,11 +2,17 @@ use clap::ValueEnum;
 use serde::Serialize;
 use serde_json::{json, Value};
 use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
 
-use super::processing::Processing;
+use super::digest::Digest;
+use super::migrate::{migrate, TOKEN_SET_VERSION};
+use super::processing::{Processing, ESCAPING_VERSION};
 
-#[derive(Clone, Copy, Debug, Serialize, ValueEnum)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ValueEnum)]
 #[serde(rename_all = "lowercase")]
 pub enum TokenType {
     /// Ext tokens 0 and 1 are used to encode bytes bit by bit. (≥2 tokens)
@@ -22,6 +28,20 @@ pub enum TokenType {
     BytesHuff,
 }
 
+impl TokenType {
+    /// Whether a token set of this type can have the given number of ext
+    /// tokens.
+    pub fn valid_n_ext_tokens(self, n_ext_tokens: usize) -> bool {
+        match self {
+            TokenType::Bits1 => n_ext_tokens == 2,
+            TokenType::Bits2 => n_ext_tokens == 4,
+            TokenType::Bits4 => n_ext_tokens == 16,
+            TokenType::Bytes => n_ext_tokens == 0,
+            TokenType::BytesHuff => n_ext_tokens >= 2,
+        }
+    }
+}
+
 impl fmt::Display for TokenType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -48,7 +68,7 @@ pub enum Token {
     Ext(u8),
 }
 
-fn bytes_to_json(bytes: &[u8]) -> Value {
+pub(crate) fn bytes_to_json(bytes: &[u8]) -> Value {
     match std::str::from_utf8(bytes) {
         Ok(s) => s.into(),
         Err(_) => json!(bytes),
@@ -62,20 +82,27 @@ pub fn show_bytes(bytes: &[u8]) -> String {
     }
 }
 
-fn parse_token(value: &Value) -> Token {
+pub(crate) fn parse_token(value: &Value) -> Option<Token> {
     match value {
-        Value::Array(v) => Token::Str(v
-            .iter()
-            .map(|b| b.as_i64().unwrap() as u8)
-            .collect::<Vec<_>>()),
-        Value::String(s) => Token::Str(s.as_bytes().to_vec()),
-        Value::Number(x) => Token::Ext(x.as_u64().unwrap() as u8),
-        _ => panic!("Unexpected token"),
+        Value::Array(v) => {
+            let bytes = v
+                .iter()
+                .map(|b| b.as_u64().filter(|&b| b < 256).map(|b| b as u8))
+                .collect::<Option<Vec<_>>>()?;
+            if bytes.is_empty() {
+                None
+            } else {
+                Some(Token::Str(bytes))
+            }
+        }
+        Value::String(s) if !s.is_empty() => Some(Token::Str(s.as_bytes().to_vec())),
+        Value::Number(x) => x.as_u64().filter(|&x| x < 256).map(|x| Token::Ext(x as u8)),
+        _ => None,
     }
 }
 
 impl Token {
-    fn to_json(&self) -> Value {
+    pub(crate) fn to_json(&self) -> Value {
         match self {
             Token::Ext(n) => (*n).into(),
             Token::Str(bytes) => bytes_to_json(bytes),
@@ -123,7 +150,7 @@ impl Sequence {
         let seq = self
             .tokens
             .iter()
-            .map(|idx| (&token_set.tokens[*idx]).to_json())
+            .map(|idx| token_set.tokens[*idx].to_json())
             .collect::<Vec<Value>>();
         json!({
             "string": bytes_to_json(self.string.as_slice()),
@@ -144,6 +171,143 @@ impl PartialOrd for Sequence {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProblemKind {
+    /// The file couldn't be read or isn't valid JSON.
+    Read(String),
+    Missing,
+    InvalidValue {
+        expected: &'static str,
+    },
+    UnknownField,
+    DuplicateToken,
+    DuplicateSequence,
+    /// A sequence has the same string as a token.
+    ShadowingSequence,
+    /// A sequence refers to a token that isn't part of the token set.
+    UnknownToken,
+    WrongExtTokenCount {
+        token_type: TokenType,
+        found: usize,
+    },
+    UnencodableByte(u8),
+    /// The token is never chosen by the tokenizer.
+    UnreachableToken(String),
+    /// The number of tokens recorded in the stats differs from the actual one.
+    WrongTokenCount {
+        recorded: u64,
+        actual: usize,
+    },
+}
+
+impl fmt::Display for ProblemKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProblemKind::Read(e) => write!(f, "{}", e),
+            ProblemKind::Missing => write!(f, "missing"),
+            ProblemKind::InvalidValue { expected } => write!(f, "expected {}", expected),
+            ProblemKind::UnknownField => write!(f, "unknown field"),
+            ProblemKind::DuplicateToken => write!(f, "duplicate token"),
+            ProblemKind::DuplicateSequence => write!(f, "duplicate sequence"),
+            ProblemKind::ShadowingSequence => write!(f, "sequence shadows a token"),
+            ProblemKind::UnknownToken => write!(f, "unknown token"),
+            ProblemKind::WrongExtTokenCount { token_type, found } => write!(
+                f,
+                "{} ext tokens is invalid for token type {}",
+                found, token_type
+            ),
+            ProblemKind::UnencodableByte(b) => write!(f, "byte {} can't be encoded", b),
+            ProblemKind::UnreachableToken(token) => write!(f, "token {} is unreachable", token),
+            ProblemKind::WrongTokenCount { recorded, actual } => write!(
+                f,
+                "recorded {} tokens, but the token set has {}",
+                recorded, actual
+            ),
+        }
+    }
+}
+
+/// A problem found in a token set, located by a JSON path such as
+/// `$.sequences[3].tokens[1]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenSetProblem {
+    pub path: String,
+    pub kind: ProblemKind,
+}
+
+impl TokenSetProblem {
+    pub(crate) fn new(path: impl Into<String>, kind: ProblemKind) -> Self {
+        TokenSetProblem {
+            path: path.into(),
+            kind,
+        }
+    }
+}
+
+impl fmt::Display for TokenSetProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.kind)
+    }
+}
+
+/// All the problems found while loading a token set.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenSetError {
+    pub problems: Vec<TokenSetProblem>,
+}
+
+impl fmt::Display for TokenSetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, problem) in self.problems.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for TokenSetError {}
+
+/// The fields of the token set JSON, including the stats written by
+/// `TokenStats::to_json`.
+const KNOWN_FIELDS: [&str; 8] = [
+    "version",
+    "type",
+    "processing",
+    "escaping",
+    "split_paragraphs",
+    "tokens",
+    "sequences",
+    "stats",
+];
+
+fn parse_token_type(value: &Value, problems: &mut Vec<TokenSetProblem>) -> Option<TokenType> {
+    let token_type = match value["type"].as_str() {
+        Some("bits1") => TokenType::Bits1,
+        Some("bits2") => TokenType::Bits2,
+        Some("bits4") => TokenType::Bits4,
+        Some("bytes") => TokenType::Bytes,
+        Some("byteshuff") => TokenType::BytesHuff,
+        None if value.get("type").is_none() => {
+            problems.push(TokenSetProblem::new("$.type", ProblemKind::Missing));
+            return None;
+        }
+        _ => {
+            problems.push(TokenSetProblem::new(
+                "$.type",
+                ProblemKind::InvalidValue {
+                    expected: "a token type",
+                },
+            ));
+            return None;
+        }
+    };
+
+    Some(token_type)
+}
+
 #[derive(Clone, Debug)]
 pub struct TokenSet {
     pub n_ext_tokens: usize,
@@ -152,6 +316,9 @@ pub struct TokenSet {
     pub token_type: TokenType,
     /// Type of pre-processing that should be done to the text before tokenization.
     pub processing: Processing,
+    /// Version of escaping of marker characters used by the processing. See
+    /// `processing::ESCAPING_VERSION`.
+    pub escaping: u32,
     /// If true, the tokens can span accross paragraphs, i.e. a token can't have
     /// any non '\n' characters after "\n\n".
     pub split_paragraphs: bool,
@@ -176,6 +343,7 @@ impl TokenSet {
             n_ext_tokens,
             token_type,
             processing,
+            escaping: ESCAPING_VERSION,
             tokens,
             sequences: Vec::new(),
             split_paragraphs,
@@ -232,79 +400,303 @@ impl TokenSet {
         token_set
     }
 
-    pub fn from_json(value: Value) -> Self {
-        let n_ext_tokens = value["tokens"]
-            .as_array()
-            .unwrap()
-            .iter()
-            .filter(|v| v.is_number())
-            .count();
+    /// Reads a token set in the current JSON layout or any of the older ones
+    /// handled by `migrate`.
+    pub fn from_json(value: Value) -> Result<Self, TokenSetError> {
+        let value = migrate(value)?;
+        let mut problems = Vec::new();
+
+        let fields = match value.as_object() {
+            Some(fields) => fields,
+            None => {
+                problems.push(TokenSetProblem::new(
+                    "$",
+                    ProblemKind::InvalidValue {
+                        expected: "an object",
+                    },
+                ));
+                return Err(TokenSetError { problems });
+            }
+        };
+        for key in fields.keys() {
+            if !KNOWN_FIELDS.contains(&key.as_str()) {
+                problems.push(TokenSetProblem::new(
+                    format!("$.{}", key),
+                    ProblemKind::UnknownField,
+                ));
+            }
+        }
+
         let processing = match value["processing"].as_str() {
-            Some("raw") => Processing::Raw,
-            Some("capswords") => Processing::CapsWords,
-            _ => panic!("Unexpected processing type."),
+            Some("raw") => Some(Processing::Raw),
+            Some("capswords") => Some(Processing::CapsWords),
+            None if value.get("processing").is_none() => {
+                problems.push(TokenSetProblem::new("$.processing", ProblemKind::Missing));
+                None
+            }
+            _ => {
+                problems.push(TokenSetProblem::new(
+                    "$.processing",
+                    ProblemKind::InvalidValue {
+                        expected: "\"raw\" or \"capswords\"",
+                    },
+                ));
+                None
+            }
         };
-        let token_type = match value["type"].as_str() {
-            Some("fallback_bits") => match value["fallback_bits"].as_i64().unwrap() {
-                1 => TokenType::Bits1,
-                2 => TokenType::Bits2,
-                4 => TokenType::Bits4,
-                _ => unreachable!(),
+        let token_type = parse_token_type(&value, &mut problems);
+        // Token sets written before escaping was introduced don't have the field.
+        let escaping = match value.get("escaping") {
+            None => 0,
+            Some(v) => match v.as_u64() {
+                Some(v) if v <= ESCAPING_VERSION as u64 => v as u32,
+                _ => {
+                    problems.push(TokenSetProblem::new(
+                        "$.escaping",
+                        ProblemKind::InvalidValue {
+                            expected: "a known escaping version",
+                        },
+                    ));
+                    0
+                }
             },
-            Some("all_tokens") => TokenType::Bytes,
-            Some("bits1") => TokenType::Bits1,
-            Some("bits2") => TokenType::Bits2,
-            Some("bits4") => TokenType::Bits4,
-            Some("bytes") => TokenType::Bytes,
-            Some("byteshuff") => TokenType::BytesHuff,
-            _ => panic!("Unknown token type"),
         };
-        let split_paragraphs = match value.get("split_paragraph") {
+        let split_paragraphs = match value.get("split_paragraphs") {
             None => false,
             Some(&Value::Bool(v)) => v,
-            _ => panic!("Can't parse split_paragraphs field."),
+            _ => {
+                problems.push(TokenSetProblem::new(
+                    "$.split_paragraphs",
+                    ProblemKind::InvalidValue {
+                        expected: "a boolean",
+                    },
+                ));
+                false
+            }
         };
 
-        let mut token_set = match token_type {
-            TokenType::Bits1 => {
-                assert_eq!(n_ext_tokens, 2);
-                TokenSet::new_bits1(processing, split_paragraphs)
-            }
-            TokenType::Bits2 => {
-                assert_eq!(n_ext_tokens, 4);
-                TokenSet::new_bits2(processing, split_paragraphs)
+        let mut ext_tokens = Vec::new();
+        let mut str_tokens: Vec<Vec<u8>> = Vec::new();
+        match value["tokens"].as_array() {
+            Some(values) => {
+                let mut seen = HashSet::new();
+                for (i, v) in values.iter().enumerate() {
+                    let path = format!("$.tokens[{}]", i);
+                    match parse_token(v) {
+                        Some(token) if !seen.insert(token.clone()) => {
+                            problems.push(TokenSetProblem::new(path, ProblemKind::DuplicateToken))
+                        }
+                        Some(Token::Ext(x)) => ext_tokens.push((path, x)),
+                        Some(Token::Str(s)) => str_tokens.push(s),
+                        None => problems.push(TokenSetProblem::new(
+                            path,
+                            ProblemKind::InvalidValue {
+                                expected: "a non-empty string, an array of bytes or a number",
+                            },
+                        )),
+                    }
+                }
             }
-            TokenType::Bits4 => {
-                assert_eq!(n_ext_tokens, 16);
-                TokenSet::new_bits4(processing, split_paragraphs)
+            None => problems.push(TokenSetProblem::new(
+                "$.tokens",
+                if value.get("tokens").is_none() {
+                    ProblemKind::Missing
+                } else {
+                    ProblemKind::InvalidValue {
+                        expected: "an array",
+                    }
+                },
+            )),
+        }
+
+        let n_ext_tokens = ext_tokens.len();
+        for (path, x) in ext_tokens {
+            if x as usize >= n_ext_tokens {
+                problems.push(TokenSetProblem::new(
+                    path,
+                    ProblemKind::InvalidValue {
+                        expected: "an ext token below the number of ext tokens",
+                    },
+                ));
             }
-            TokenType::Bytes => {
-                assert_eq!(n_ext_tokens, 0);
-                TokenSet::new(0, processing, TokenType::Bytes, split_paragraphs)
+        }
+        if let Some(token_type) = token_type {
+            if !token_type.valid_n_ext_tokens(n_ext_tokens) {
+                problems.push(TokenSetProblem::new(
+                    "$.tokens",
+                    ProblemKind::WrongExtTokenCount {
+                        token_type,
+                        found: n_ext_tokens,
+                    },
+                ));
             }
+        }
+
+        let (processing, token_type) = match (processing, token_type) {
+            (Some(processing), Some(token_type)) if problems.is_empty() => (processing, token_type),
+            _ => return Err(TokenSetError { problems }),
+        };
+
+        let mut token_set = match token_type {
+            TokenType::Bits1 => TokenSet::new_bits1(processing, split_paragraphs),
+            TokenType::Bits2 => TokenSet::new_bits2(processing, split_paragraphs),
+            TokenType::Bits4 => TokenSet::new_bits4(processing, split_paragraphs),
             other => TokenSet::new(n_ext_tokens, processing, other, split_paragraphs),
         };
-        for token in value["tokens"].as_array().unwrap().iter() {
-            if let Token::Str(token) = parse_token(token) {
-                token_set.add_token(&token);
-            }
+        token_set.escaping = escaping;
+
+        for token in str_tokens {
+            token_set.add_token(&token);
         }
 
-        if value.as_object().unwrap().contains_key("sequences") {
-            for seq in value["sequences"].as_array().unwrap().iter() {
-                let string = if let Token::Str(s) = parse_token(&seq["string"]) { s } else {panic!()};
+        if let Some(sequences) = value.get("sequences") {
+            let sequences = match sequences.as_array() {
+                Some(sequences) => sequences,
+                None => {
+                    problems.push(TokenSetProblem::new(
+                        "$.sequences",
+                        ProblemKind::InvalidValue {
+                            expected: "an array",
+                        },
+                    ));
+                    return Err(TokenSetError { problems });
+                }
+            };
+
+            let mut seen = HashSet::new();
+
+            for (i, seq) in sequences.iter().enumerate() {
+                let path = format!("$.sequences[{}]", i);
+                let string = match parse_token(&seq["string"]) {
+                    Some(Token::Str(s)) => s,
+                    _ => {
+                        problems.push(TokenSetProblem::new(
+                            format!("{}.string", path),
+                            ProblemKind::InvalidValue {
+                                expected: "a non-empty string or an array of bytes",
+                            },
+                        ));
+                        continue;
+                    }
+                };
+                if !seen.insert(string.clone()) {
+                    problems.push(TokenSetProblem::new(path, ProblemKind::DuplicateSequence));
+                    continue;
+                }
+                if token_set.find_token(&string).is_some() {
+                    problems.push(TokenSetProblem::new(
+                        format!("{}.string", path),
+                        ProblemKind::ShadowingSequence,
+                    ));
+                    continue;
+                }
+
+                let token_values = match seq["tokens"].as_array() {
+                    Some(values) if !values.is_empty() => values,
+                    _ => {
+                        problems.push(TokenSetProblem::new(
+                            format!("{}.tokens", path),
+                            ProblemKind::InvalidValue {
+                                expected: "a non-empty array",
+                            },
+                        ));
+                        continue;
+                    }
+                };
                 let mut tokens = Vec::new();
-                for token in seq["tokens"].as_array().unwrap().iter() {
-                    let token = parse_token(token);
-                    let idx = token_set.tokens.iter().position(|x| x == &token).unwrap();
-                    tokens.push(idx);
+                for (j, v) in token_values.iter().enumerate() {
+                    let idx = parse_token(v)
+                        .and_then(|token| token_set.tokens.iter().position(|x| x == &token));
+                    match idx {
+                        Some(idx) => tokens.push(idx),
+                        None => problems.push(TokenSetProblem::new(
+                            format!("{}.tokens[{}]", path, j),
+                            ProblemKind::UnknownToken,
+                        )),
+                    }
                 }
 
+                // Bits* token sets already contain generated sequences for
+                // all bytes.
+                token_set.sequences.retain(|s| s.string != string);
                 token_set.add_sequence(string, tokens);
             }
         }
 
-        token_set
+        problems.extend(token_set.validate());
+
+        if problems.is_empty() {
+            Ok(token_set)
+        } else {
+            Err(TokenSetError { problems })
+        }
+    }
+
+    /// Checks the invariants of the token set: all `Str` tokens and sequences
+    /// are distinct, no sequence shadows a token, the sequences refer to
+    /// existing tokens, the number of ext tokens matches the type, and every
+    /// byte can be encoded. The paths of the problems refer to the layout
+    /// written by `to_json`.
+    pub fn validate(&self) -> Vec<TokenSetProblem> {
+        let mut problems = Vec::new();
+
+        let mut strings = HashSet::new();
+        let mut n_ext_tokens = 0;
+        for (i, token) in self.tokens.iter().enumerate() {
+            match token {
+                Token::Str(s) => {
+                    if !strings.insert(s.as_slice()) {
+                        problems.push(TokenSetProblem::new(
+                            format!("$.tokens[{}]", i),
+                            ProblemKind::DuplicateToken,
+                        ));
+                    }
+                }
+                Token::Ext(_) => n_ext_tokens += 1,
+            }
+        }
+
+        if n_ext_tokens != self.n_ext_tokens || !self.token_type.valid_n_ext_tokens(n_ext_tokens) {
+            problems.push(TokenSetProblem::new(
+                "$.tokens",
+                ProblemKind::WrongExtTokenCount {
+                    token_type: self.token_type,
+                    found: n_ext_tokens,
+                },
+            ));
+        }
+
+        let token_strings = strings.clone();
+
+        for (i, seq) in self.sequences.iter().enumerate() {
+            if token_strings.contains(seq.string.as_slice()) {
+                problems.push(TokenSetProblem::new(
+                    format!("$.sequences[{}]", i),
+                    ProblemKind::ShadowingSequence,
+                ));
+            } else if !strings.insert(seq.string.as_slice()) {
+                problems.push(TokenSetProblem::new(
+                    format!("$.sequences[{}]", i),
+                    ProblemKind::DuplicateSequence,
+                ));
+            }
+            for (j, &token) in seq.tokens.iter().enumerate() {
+                if token >= self.tokens.len() {
+                    problems.push(TokenSetProblem::new(
+                        format!("$.sequences[{}].tokens[{}]", i, j),
+                        ProblemKind::UnknownToken,
+                    ));
+                }
+            }
+        }
+
+        for b in 0..=255 {
+            if !strings.contains([b].as_slice()) {
+                problems.push(TokenSetProblem::new("$", ProblemKind::UnencodableByte(b)));
+            }
+        }
+
+        problems
     }
 
     pub fn name(&self) -> String {
@@ -316,7 +708,7 @@ impl TokenSet {
         )
     }
 
-    /// Returns the minimum number of Ext and single-byte tokens that a 
+    /// Returns the minimum number of Ext and single-byte tokens that a
     /// tokenset of this type can have.
     pub fn min_bytes_ext_tokens(&self) -> usize {
         match self.token_type {
@@ -394,11 +786,15 @@ impl TokenSet {
 
     pub fn to_json(&self) -> Value {
         let mut value = json!({
+            "version": TOKEN_SET_VERSION,
             "type": self.token_type,
             "processing": self.processing,
             "tokens": self.tokens.iter().map(|t| t.to_json()).collect::<Vec<_>>(),
             "split_paragraphs": self.split_paragraphs,
         });
+        if self.processing == Processing::CapsWords {
+            value["escaping"] = self.escaping.into();
+        }
         let sequences = self
             .sequences
             .iter()
@@ -414,7 +810,34 @@ impl TokenSet {
     pub fn sort(&mut self) {
         let mut token_idxs = (0..self.tokens.len()).collect::<Vec<usize>>();
         token_idxs.sort_by_key(|&id| &self.tokens[id]);
+        self.reorder(&token_idxs);
+    }
+
+    /// Puts the tokens of `prefix` first, in the same order, and the others
+    /// after them in sorted order, so that the IDs of a smaller token set
+    /// stay valid in this one. All tokens of `prefix` must be in the set.
+    pub fn put_first(&mut self, prefix: &[Token]) {
+        let ids = self
+            .tokens
+            .iter()
+            .enumerate()
+            .map(|(id, token)| (token, id))
+            .collect::<HashMap<_, _>>();
+        let mut token_idxs = prefix.iter().map(|token| ids[token]).collect::<Vec<_>>();
+
+        let mut rest = (0..self.tokens.len()).collect::<Vec<usize>>();
+        for &id in token_idxs.iter() {
+            rest[id] = usize::MAX;
+        }
+        rest.retain(|&id| id != usize::MAX);
+        rest.sort_by_key(|&id| &self.tokens[id]);
+        token_idxs.extend(rest);
 
+        self.reorder(&token_idxs);
+    }
+
+    /// Moves the token with ID `token_idxs[i]` to position `i`.
+    fn reorder(&mut self, token_idxs: &[usize]) {
         let mut new_indices = vec![0; self.tokens.len()];
         for (new_pos, &current_pos) in token_idxs.iter().enumerate() {
             new_indices[current_pos] = new_pos;
@@ -427,9 +850,63 @@ impl TokenSet {
             }
         }
 
-        self.tokens.sort();
+        self.tokens = token_idxs
+            .iter()
+            .map(|&id| self.tokens[id].clone())
+            .collect();
         self.sequences.sort();
     }
+
+    /// A stable digest of everything that affects the tokenization. The order
+    /// of the tokens matters, so sets compared this way should be sorted.
+    pub fn digest(&self) -> u128 {
+        let mut digest = Digest::new();
+        digest.write_bytes(self.token_type.to_string().as_bytes());
+        digest.write_bytes(self.processing.to_string().as_bytes());
+        digest.write_u64(self.escaping as u64);
+        digest.write_u64(self.split_paragraphs as u64);
+
+        digest.write_u64(self.tokens.len() as u64);
+        for token in self.tokens.iter() {
+            match token {
+                Token::Str(s) => {
+                    digest.write(&[0]);
+                    digest.write_bytes(s);
+                }
+                &Token::Ext(x) => digest.write(&[1, x]),
+            }
+        }
+
+        digest.write_u64(self.sequences.len() as u64);
+        for seq in self.sequences.iter() {
+            digest.write_bytes(&seq.string);
+            digest.write_u64(seq.tokens.len() as u64);
+            for &id in seq.tokens.iter() {
+                digest.write_u64(id as u64);
+            }
+        }
+        digest.finish()
+    }
+}
+
+fn read_error(e: String) -> TokenSetError {
+    TokenSetError {
+        problems: vec![TokenSetProblem::new("$", ProblemKind::Read(e))],
+    }
+}
+
+/// Reads the JSON of a token set file without interpreting it.
+pub fn read_token_set_json(filename: &str) -> Result<Value, TokenSetError> {
+    let path = Path::new(filename);
+    let input_tokens_file = File::open(path).map_err(|e| read_error(e.to_string()))?;
+    let reader = BufReader::new(input_tokens_file);
+
+    serde_json::from_reader(reader).map_err(|e| read_error(e.to_string()))
+}
+
+/// Reads a token set from a JSON file.
+pub fn read_token_set(filename: &str) -> Result<TokenSet, TokenSetError> {
+    TokenSet::from_json(read_token_set_json(filename)?)
 }
 
 #[cfg(test)]
@@ -462,8 +939,111 @@ mod tests {
         assert_eq!(value["type"], "bits4");
         assert_eq!(value["tokens"].as_array().unwrap().len(), 19);
         assert_eq!(value["sequences"].as_array().unwrap().len(), 253);
+        assert!(value.get("escaping").is_none());
+
+        let new_token_set = TokenSet::from_json(value).unwrap();
+        assert_eq!(new_token_set.sequences.len(), 253);
+        assert!(new_token_set.split_paragraphs);
+    }
+
+    #[test]
+    fn token_set_escaping_json() {
+        let token_set = TokenSet::new_bits4(Processing::CapsWords, true);
+        let mut value = token_set.to_json();
+        assert_eq!(value["escaping"], ESCAPING_VERSION);
+        assert_eq!(
+            TokenSet::from_json(value.clone()).unwrap().escaping,
+            ESCAPING_VERSION
+        );
+
+        value.as_object_mut().unwrap().remove("escaping");
+        assert_eq!(TokenSet::from_json(value).unwrap().escaping, 0);
+    }
+
+    fn problem_paths(value: Value) -> Vec<String> {
+        TokenSet::from_json(value)
+            .unwrap_err()
+            .problems
+            .into_iter()
+            .map(|p| p.path)
+            .collect()
+    }
+
+    #[test]
+    fn from_json_errors() {
+        let value = json!({
+            "type": "bits2",
+            "processing": "raw",
+            "split_paragraph": true,
+            "tokens": [0, 1, 2, 3, "a", "b", "a", ""],
+        });
+        assert_eq!(
+            problem_paths(value),
+            vec!["$.split_paragraph", "$.tokens[6]", "$.tokens[7]"]
+        );
+
+        let value = json!({
+            "type": "bits4",
+            "processing": "raw",
+            "tokens": [0, 1, 2],
+        });
+        let error = TokenSet::from_json(value).unwrap_err();
+        assert_eq!(
+            error.problems,
+            vec![TokenSetProblem::new(
+                "$.tokens",
+                ProblemKind::WrongExtTokenCount {
+                    token_type: TokenType::Bits4,
+                    found: 3
+                }
+            )]
+        );
+        assert_eq!(
+            error.to_string(),
+            "$.tokens: 3 ext tokens is invalid for token type bits4"
+        );
+
+        let value = json!({
+            "type": "byteshuff",
+            "processing": "capswords",
+            "tokens": [0, 1, "a", "b"],
+            "sequences": [
+                {"string": "c", "tokens": ["a", 0]},
+                {"string": "c", "tokens": ["a", 1]},
+                {"string": "d", "tokens": ["a", 2]},
+                {"string": "b", "tokens": ["a", 1]},
+            ],
+        });
+        let paths = problem_paths(value);
+        assert_eq!(
+            &paths[..3],
+            [
+                "$.sequences[1]",
+                "$.sequences[2].tokens[1]",
+                "$.sequences[3].string"
+            ]
+        );
+        assert_eq!(paths.len(), 3 + 256 - 4);
+    }
+
+    #[test]
+    fn validate() {
+        let mut token_set = TokenSet::new_bits4(Processing::Raw, true);
+        token_set.add_token("ab".as_bytes());
+        assert!(token_set.validate().is_empty());
 
-        let _new_token_set = TokenSet::from_json(value);
+        token_set.add_token("ab".as_bytes());
+        token_set.add_sequence("ab".as_bytes().to_vec(), vec![100]);
+        token_set.sequences.retain(|s| s.string != [0]);
+        assert_eq!(
+            token_set.validate(),
+            vec![
+                TokenSetProblem::new("$.tokens[17]", ProblemKind::DuplicateToken),
+                TokenSetProblem::new("$.sequences[255]", ProblemKind::ShadowingSequence),
+                TokenSetProblem::new("$.sequences[255].tokens[0]", ProblemKind::UnknownToken),
+                TokenSetProblem::new("$", ProblemKind::UnencodableByte(0)),
+            ]
+        );
     }
 
     #[test]
@@ -510,6 +1090,50 @@ mod tests {
         );
     }
 
+    #[test]
+    fn put_first() {
+        let mut token_set = TokenSet::new(2, Processing::Raw, TokenType::BytesHuff, true);
+        token_set.add_token("b".as_bytes()); // 2
+        token_set.add_token("a".as_bytes()); // 3
+        token_set.add_token("c".as_bytes()); // 4
+        token_set.add_sequence("d".as_bytes().to_vec(), vec![2, 3, 1]); // "b", "a", 1
+
+        let prefix = [Token::Str("c".as_bytes().to_vec()), Token::Ext(1)];
+        token_set.put_first(&prefix);
+
+        assert_eq!(token_set.tokens[0], Token::Str("c".as_bytes().to_vec()));
+        assert_eq!(token_set.tokens[1], Token::Ext(1));
+        assert_eq!(token_set.tokens[2], Token::Ext(0));
+        assert_eq!(token_set.tokens[3], Token::Str("a".as_bytes().to_vec()));
+        assert_eq!(token_set.tokens[4], Token::Str("b".as_bytes().to_vec()));
+        assert_eq!(token_set.sequences[0].tokens, vec![4, 3, 1]);
+    }
+
+    #[test]
+    fn digest() {
+        let mut first = TokenSet::new_bytes(Processing::Raw);
+        first.add_token(b"ab");
+        first.add_token(b"cd");
+        let mut second = TokenSet::new_bytes(Processing::Raw);
+        second.add_token(b"cd");
+        second.add_token(b"ab");
+        assert_ne!(first.digest(), second.digest());
+
+        first.sort();
+        second.sort();
+        assert_eq!(first.digest(), second.digest());
+
+        let mut capswords = TokenSet::new_bytes(Processing::CapsWords);
+        capswords.add_token(b"ab");
+        capswords.add_token(b"cd");
+        capswords.sort();
+        assert_ne!(first.digest(), capswords.digest());
+
+        second.add_token(b"abcd");
+        second.sort();
+        assert_ne!(first.digest(), second.digest());
+    }
+
     #[test]
     fn remove_token() {
         let mut token_set = TokenSet::new(2, Processing::Raw, TokenType::BytesHuff, true);
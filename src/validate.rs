@@ -0,0 +1,86 @@
+use serde_json::Value;
+
+use super::tokenizer2::FragmentTokenizer;
+use super::tokenset::{read_token_set_json, ProblemKind, TokenSet, TokenSetProblem};
+
+/// Checks a token set in the JSON format. In addition to the problems found
+/// by `TokenSet::from_json`, reports the ext tokens unreachable for the tokenizer
+/// and the mismatch between the recorded and actual number of tokens.
+pub fn check_token_set_json(value: Value) -> Vec<TokenSetProblem> {
+    let recorded_ntokens = value["stats"]["ntokens"].as_u64();
+
+    let token_set = match TokenSet::from_json(value) {
+        Ok(token_set) => token_set,
+        Err(e) => return e.problems,
+    };
+
+    let mut problems = Vec::new();
+
+    if let Some(recorded) = recorded_ntokens {
+        if recorded != token_set.ntokens() as u64 {
+            problems.push(TokenSetProblem {
+                path: "$.stats.ntokens".to_string(),
+                kind: ProblemKind::WrongTokenCount {
+                    recorded,
+                    actual: token_set.ntokens(),
+                },
+            });
+        }
+    }
+
+    let tokenizer = FragmentTokenizer::new(token_set);
+    for idx in tokenizer.unreachable_ext_tokens() {
+        problems.push(TokenSetProblem {
+            path: format!("$.tokens[{}]", idx),
+            kind: ProblemKind::UnreachableToken(tokenizer.token_set.tokens[idx].to_string()),
+        });
+    }
+
+    problems
+}
+
+/// Checks a token set file. See `check_token_set_json`.
+pub fn check_token_set_file(filename: &str) -> Vec<TokenSetProblem> {
+    match read_token_set_json(filename) {
+        Ok(value) => check_token_set_json(value),
+        Err(e) => e.problems,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn check() {
+        let value = json!({
+            "type": "byteshuff",
+            "processing": "raw",
+            "tokens": [0, 1, 2, "a", "b"],
+            "sequences": (0..=255u8)
+                .filter(|&b| b != b'a' && b != b'b')
+                .map(|b| json!({"string": [b], "tokens": ["a", b % 2]}))
+                .collect::<Vec<_>>(),
+            "stats": {"ntokens": 6},
+        });
+
+        let problems = check_token_set_json(value);
+        assert_eq!(
+            problems,
+            vec![
+                TokenSetProblem {
+                    path: "$.stats.ntokens".to_string(),
+                    kind: ProblemKind::WrongTokenCount {
+                        recorded: 6,
+                        actual: 5
+                    }
+                },
+                TokenSetProblem {
+                    path: "$.tokens[2]".to_string(),
+                    kind: ProblemKind::UnreachableToken("2".to_string())
+                },
+            ]
+        );
+    }
+}